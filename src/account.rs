@@ -0,0 +1,261 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Instant, SystemTime},
+};
+
+use crate::{
+    currency::{Currency, FxRates},
+    money::Money,
+    AccId, OrderId, SecId,
+};
+
+/// Seconds in the year borrow rates are quoted over
+const YEAR_SECS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+/// Entries kept on the statement of each account, dropping the oldest
+const MAX_STATEMENT: usize = 10_000;
+
+/// Shares and cash held by an account
+#[derive(Debug, Default)]
+pub struct Account {
+    /// Shares held in each security, negative for short positions
+    pub holdings: HashMap<SecId, i64>,
+    /// Cash in the account's own currency. Bids of accounts not trading on margin
+    /// must be covered by the cash held in the currency of the security.
+    pub cash: Money,
+    /// Currency the account is valued in
+    pub currency: Currency,
+    /// Cash held in every other currency
+    pub balances: HashMap<Currency, Money>,
+    /// Shares borrowed to cover each short position
+    pub borrows: HashMap<SecId, Borrow>,
+    /// Trades only on cash when unset
+    pub margin: Option<Margin>,
+    /// Orders placed to close each position after a margin call
+    pub liquidations: HashMap<SecId, OrderId>,
+    /// Picks the fees charged from the schedules of each security
+    pub fee_tier: usize,
+    /// Latest cash paid in or out other than by trading, oldest first
+    pub statement: VecDeque<StatementEntry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum EntryKind {
+    Deposit,
+    /// Cash exchanged from one currency to another, posted once in each
+    Conversion,
+    /// Paid on the shares held at the record time, charged to short positions
+    Dividend {
+        sec: SecId,
+        shares: i64,
+        per_share: Money,
+    },
+    /// Fraction of a share left over from a split, settled at the new price
+    CashInLieu {
+        sec: SecId,
+    },
+    /// Position closed at the final price of a delisted security
+    CashOut {
+        sec: SecId,
+        shares: i64,
+        price: Money,
+    },
+    /// Strike paid or received for shares delivered on exercise of an option
+    /// series, `contracts` negative for written ones
+    Exercise {
+        sec: SecId,
+        contracts: i64,
+        strike: Money,
+    },
+    /// Change in value of a futures position since its last settlement, marked to
+    /// `price`
+    VariationMargin {
+        sec: SecId,
+        contracts: i64,
+        price: Money,
+    },
+}
+
+/// Line of an account statement
+#[derive(Debug, Clone)]
+pub struct StatementEntry {
+    pub time: SystemTime,
+    pub kind: EntryKind,
+    pub currency: Currency,
+    /// Negative when paid out of the account
+    pub amount: Money,
+    /// Cash left in the currency after the entry
+    pub balance: Money,
+}
+
+/// Share of the value of its positions an account trading on margin must cover
+/// with its own equity
+#[derive(Debug, Clone, Copy)]
+pub struct Margin {
+    /// Required to open new positions
+    pub initial: f64,
+    /// Positions are liquidated when equity falls below this
+    pub maintenance: f64,
+}
+
+/// Notice that the equity of an account fell below its maintenance margin
+#[derive(Debug, Clone)]
+pub struct MarginCall {
+    pub account: AccId,
+    pub equity: Money,
+    pub requirement: Money,
+}
+
+/// Shares an account has borrowed to sell short
+#[derive(Debug)]
+pub struct Borrow {
+    pub opened: Instant,
+    /// Time up to which fees have been charged
+    pub accrued_to: Instant,
+    /// Total fees charged over the life of the borrow
+    pub fees: Money,
+    /// Fees and dividends the account had no cash to pay, which force a buy-in
+    pub owed: Money,
+    /// Order placed to buy back the shares once the short limit was breached
+    pub buy_in: Option<OrderId>,
+}
+
+impl Borrow {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            opened: now,
+            accrued_to: now,
+            fees: Money::ZERO,
+            owed: Money::ZERO,
+            buy_in: None,
+        }
+    }
+}
+
+impl Account {
+    pub fn shares(&self, sec_id: SecId) -> i64 {
+        self.holdings.get(&sec_id).copied().unwrap_or(0)
+    }
+
+    /// Cash held in `currency`
+    pub fn cash(&self, currency: &Currency) -> Money {
+        if *currency == self.currency {
+            self.cash
+        } else {
+            self.balances.get(currency).copied().unwrap_or_default()
+        }
+    }
+
+    pub fn cash_mut(&mut self, currency: &Currency) -> &mut Money {
+        if *currency == self.currency {
+            &mut self.cash
+        } else {
+            self.balances.entry(currency.clone()).or_default()
+        }
+    }
+
+    /// Cash in every currency converted to the account's own at `rates`, leaving out
+    /// currencies without a rate. Saturates rather than overflowing.
+    pub fn cash_value(&self, rates: &FxRates) -> Money {
+        self.balances
+            .iter()
+            .filter_map(|(currency, cash)| rates.convert(*cash, currency, &self.currency))
+            .fold(self.cash, Money::saturating_add)
+    }
+
+    /// Cash plus the value of every position at `prices`, all in the account's
+    /// currency. Saturates rather than overflowing.
+    pub fn equity(&self, prices: &HashMap<SecId, Money>, rates: &FxRates) -> Money {
+        self.holdings
+            .iter()
+            .map(|(sec_id, shares)| {
+                let price = prices.get(sec_id).copied().unwrap_or_default();
+                price.saturating_mul(*shares)
+            })
+            .fold(self.cash_value(rates), Money::saturating_add)
+    }
+
+    /// Value of long and short positions together at `prices`. Saturates rather
+    /// than overflowing.
+    pub fn exposure(&self, prices: &HashMap<SecId, Money>) -> Money {
+        self.holdings
+            .iter()
+            .map(|(sec_id, shares)| {
+                let price = prices.get(sec_id).copied().unwrap_or_default();
+                price.saturating_mul(shares.saturating_abs())
+            })
+            .fold(Money::ZERO, Money::saturating_add)
+    }
+
+    /// Pays `amount` of `currency` into the account, recording it on the statement
+    pub fn post(
+        &mut self,
+        kind: EntryKind,
+        currency: &Currency,
+        amount: Money,
+        time: SystemTime,
+    ) -> StatementEntry {
+        let cash = self.cash_mut(currency);
+        *cash += amount;
+        let entry = StatementEntry {
+            time,
+            kind,
+            currency: currency.clone(),
+            amount,
+            balance: *cash,
+        };
+        if self.statement.len() == MAX_STATEMENT {
+            self.statement.pop_front();
+        }
+        self.statement.push_back(entry.clone());
+        entry
+    }
+
+    /// Adds `change` shares to the position, opening a borrow when it goes short
+    /// and closing it once it no longer is. Returns the borrow if it was closed.
+    pub fn adjust_shares(&mut self, sec_id: SecId, change: i64, now: Instant) -> Option<Borrow> {
+        let shares = self.holdings.entry(sec_id).or_default();
+        *shares += change;
+        if *shares < 0 {
+            self.borrows
+                .entry(sec_id)
+                .or_insert_with(|| Borrow::new(now));
+            None
+        } else {
+            self.borrows.remove(&sec_id)
+        }
+    }
+
+    /// Charges the fee on the borrowed shares of `sec_id`, worth `value` in
+    /// `currency`, since it was last charged at `rate` a year. Fees too small to
+    /// charge carry over to the next call, and those the cash held does not cover
+    /// are owed until it does. Returns the fee paid.
+    pub fn accrue_borrow_fee(
+        &mut self,
+        sec_id: SecId,
+        value: Money,
+        currency: &Currency,
+        rate: f64,
+        now: Instant,
+    ) -> Money {
+        let Some(borrow) = self.borrows.get_mut(&sec_id) else {
+            return Money::ZERO;
+        };
+        let elapsed = now.duration_since(borrow.accrued_to).as_secs_f64();
+        let fee = value.scale_by(rate * elapsed / YEAR_SECS);
+        if fee.is_positive() {
+            borrow.owed += fee;
+            borrow.accrued_to = now;
+        }
+        let owed = borrow.owed;
+        if !owed.is_positive() {
+            return Money::ZERO;
+        }
+        let cash = self.cash_mut(currency);
+        let paid = owed.min((*cash).max(Money::ZERO));
+        *cash -= paid;
+        let borrow = self.borrows.get_mut(&sec_id).unwrap();
+        borrow.owed -= paid;
+        borrow.fees += paid;
+        paid
+    }
+}
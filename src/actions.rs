@@ -0,0 +1,50 @@
+use std::time::SystemTime;
+
+use crate::{money::Money, AccId, SecId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+    /// Every `from` shares became `to` shares, prices moving the other way
+    Split { to: usize, from: usize },
+    /// Cash paid at `pay` on every share held at `record`
+    Dividend {
+        per_share: Money,
+        record: SystemTime,
+        pay: SystemTime,
+    },
+    /// New shares created in the issuer's account, offered at `price` if set
+    Issue {
+        quantity: usize,
+        price: Option<Money>,
+    },
+    /// Shares retired by the issuer, bought on the book at up to `price` if set
+    Buyback {
+        quantity: usize,
+        price: Option<Money>,
+    },
+    /// Trading ended for good, positions closed at `price` if set
+    Delisting { price: Option<Money> },
+    /// Option series expired, exercised if in the money at the underlying's `price`
+    Expiry { price: Money, exercised: bool },
+    /// Futures series expired, its positions settled in cash at the underlying's
+    /// `price`
+    FinalSettlement { price: Money },
+}
+
+/// Change made by the issuer of a security, affecting everyone holding it
+#[derive(Debug, Clone, Copy)]
+pub struct CorporateAction {
+    pub sec: SecId,
+    pub time: SystemTime,
+    pub kind: ActionKind,
+}
+
+/// Cash dividend declared on a security and not yet paid
+#[derive(Debug, Clone)]
+pub struct Dividend {
+    pub per_share: Money,
+    pub record: SystemTime,
+    pub pay: SystemTime,
+    /// Shares of each holder at the record time, once it has passed
+    pub holders: Option<Vec<(AccId, i64)>>,
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use crate::{money::Money, SecId};
+
+/// Shares of other securities backing a basket security. Shares of the basket are
+/// created and redeemed a unit at a time, each unit swapping for `components`.
+#[derive(Debug, Clone)]
+pub struct Basket {
+    /// Shares of each security in one unit
+    pub components: Vec<(SecId, usize)>,
+    /// Shares of the basket in one unit
+    pub unit_size: usize,
+}
+
+impl Basket {
+    pub fn contains(&self, sec_id: SecId) -> bool {
+        self.components
+            .iter()
+            .any(|(component, _)| *component == sec_id)
+    }
+
+    /// Shares of each security making up `units` units, None if any are more than
+    /// can be held
+    pub fn required(&self, units: usize) -> Option<Vec<(SecId, i64)>> {
+        self.components
+            .iter()
+            .map(|(sec_id, quantity)| {
+                let required = i64::try_from(quantity.checked_mul(units)?).ok()?;
+                Some((*sec_id, required))
+            })
+            .collect()
+    }
+
+    /// Indicative net asset value of one share of the basket at `prices`
+    pub fn nav(&self, prices: &HashMap<SecId, Money>) -> Money {
+        let unit: Money = self
+            .components
+            .iter()
+            .map(|(sec_id, quantity)| {
+                prices.get(sec_id).copied().unwrap_or_default() * *quantity as i64
+            })
+            .sum();
+        Money(unit.minor_units() / self.unit_size as i64)
+    }
+
+    /// Whether the shares of `sec_id` in a unit can be held after every share became
+    /// `to`
+    pub fn can_split(&self, sec_id: SecId, to: usize) -> bool {
+        self.components
+            .iter()
+            .all(|(component, quantity)| *component != sec_id || quantity.checked_mul(to).is_some())
+    }
+
+    /// Restates the shares of `sec_id` in a unit after it split, dropping fractions
+    pub fn split(&mut self, sec_id: SecId, to: usize, from: usize) {
+        for (component, quantity) in self.components.iter_mut() {
+            if *component == sec_id {
+                *quantity = *quantity * to / from;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn basket() -> (Basket, SecId, SecId) {
+        let [a, b] = [(); 2].map(|_| SecId(Uuid::new_v4()));
+        let basket = Basket {
+            components: vec![(a, 2), (b, 1)],
+            unit_size: 10,
+        };
+        (basket, a, b)
+    }
+
+    #[test]
+    fn required_scales_every_component() {
+        let (basket, a, b) = basket();
+        assert_eq!(basket.required(3), Some(vec![(a, 6), (b, 3)]));
+        assert_eq!(basket.required(usize::MAX), None);
+        assert_eq!(basket.required(i64::MAX as usize), None);
+    }
+
+    #[test]
+    fn nav_is_per_share_of_the_basket() {
+        let (basket, a, b) = basket();
+        let prices = HashMap::from([(a, Money(100_000)), (b, Money(50_000))]);
+        assert_eq!(basket.nav(&prices), Money(25_000));
+        // Components without a price count for nothing
+        let prices = HashMap::from([(a, Money(100_000))]);
+        assert_eq!(basket.nav(&prices), Money(20_000));
+    }
+
+    #[test]
+    fn split_restates_only_the_component_split() {
+        let (mut basket, a, b) = basket();
+        basket.split(a, 3, 2);
+        assert_eq!(basket.components, vec![(a, 3), (b, 1)]);
+        basket.split(b, 1, 2);
+        assert_eq!(basket.components, vec![(a, 3), (b, 0)]);
+        assert!(basket.can_split(a, 1_000));
+        assert!(!basket.can_split(a, usize::MAX));
+        assert!(basket.can_split(SecId(Uuid::new_v4()), usize::MAX));
+    }
+}
@@ -1,26 +1,100 @@
-use std::cmp::Reverse;
+use std::{cmp::Reverse, time::SystemTime};
 
-use crate::AccId;
+use uuid::Uuid;
 
-/// Always buy at lowest price
+use crate::{AccId, OrderId};
+
+/// Price as a whole number of a security's tick size
+pub type Ticks = i64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// How long an order stays on the book
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimeInForce {
+    #[default]
+    GoodTillCancel,
+    /// Expires at the close of the session it was placed in
+    Day,
+    GoodTillTime(SystemTime),
+}
+
+/// Always buy at lowest price, then earliest placed
 #[derive(Debug)]
 pub struct Bid {
-    pub price: Reverse<ordered_float::NotNan<f64>>,
+    pub id: OrderId,
+    pub price: Reverse<Ticks>,
+    /// Shares shown on the book
+    pub quantity: usize,
+    /// Shares held back to replenish `quantity`
+    pub hidden: usize,
+    /// Size of each shown slice of an iceberg order
+    pub display: Option<usize>,
+    pub expires_at: Option<SystemTime>,
     pub account: AccId,
+    /// Position in time priority, lower was placed first
+    pub seq: u64,
 }
 
 impl Bid {
-    pub fn new(acc: AccId, price: f64) -> Self {
+    pub fn new(acc: AccId, price: Ticks, quantity: usize, seq: u64) -> Self {
         Self {
-            price: Reverse(ordered_float::NotNan::new(price).unwrap()),
+            id: OrderId(Uuid::new_v4()),
+            price: Reverse(price),
+            quantity,
+            hidden: 0,
+            display: None,
+            expires_at: None,
             account: acc,
+            seq,
         }
     }
+
+    /// Shows only `display` shares at a time, keeping the rest in reserve
+    pub fn with_display(mut self, display: Option<usize>) -> Self {
+        self.display = display;
+        self.set_remaining(self.remaining());
+        self
+    }
+
+    pub fn with_expiry(mut self, expires_at: Option<SystemTime>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expiry| expiry <= now)
+    }
+
+    /// Shown and hidden shares left on the order
+    pub fn remaining(&self) -> usize {
+        self.quantity + self.hidden
+    }
+
+    pub fn set_remaining(&mut self, remaining: usize) {
+        self.quantity = self.display.map_or(remaining, |d| d.min(remaining));
+        self.hidden = remaining - self.quantity;
+    }
+
+    /// Shows the next slice once the shown one is used up, moving the order to the
+    /// back of the queue. Returns true if it was replenished.
+    pub fn replenish(&mut self, seq: u64) -> bool {
+        if self.quantity > 0 || self.hidden == 0 {
+            return false;
+        }
+        self.set_remaining(self.hidden);
+        self.seq = seq;
+        true
+    }
 }
 
 impl PartialEq for Bid {
     fn eq(&self, other: &Self) -> bool {
-        self.price == other.price
+        self.price == other.price && self.seq == other.seq
     }
 }
 
@@ -33,29 +107,84 @@ impl Eq for Bid {}
 
 impl Ord for Bid {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.price.cmp(&other.price)
+        self.price
+            .cmp(&other.price)
+            .then_with(|| other.seq.cmp(&self.seq))
     }
 }
 
-/// Always sell at highest price
+/// Always sell at highest price, then earliest placed
 #[derive(Debug)]
 pub struct Ask {
-    pub price: ordered_float::NotNan<f64>,
+    pub id: OrderId,
+    pub price: Ticks,
+    /// Shares shown on the book
+    pub quantity: usize,
+    /// Shares held back to replenish `quantity`
+    pub hidden: usize,
+    /// Size of each shown slice of an iceberg order
+    pub display: Option<usize>,
+    pub expires_at: Option<SystemTime>,
     pub account: AccId,
+    /// Position in time priority, lower was placed first
+    pub seq: u64,
 }
 
 impl Ask {
-    pub fn new(acc: AccId, price: f64) -> Self {
+    pub fn new(acc: AccId, price: Ticks, quantity: usize, seq: u64) -> Self {
         Self {
-            price: ordered_float::NotNan::new(price).unwrap(),
+            id: OrderId(Uuid::new_v4()),
+            price,
+            quantity,
+            hidden: 0,
+            display: None,
+            expires_at: None,
             account: acc,
+            seq,
+        }
+    }
+
+    /// Shows only `display` shares at a time, keeping the rest in reserve
+    pub fn with_display(mut self, display: Option<usize>) -> Self {
+        self.display = display;
+        self.set_remaining(self.remaining());
+        self
+    }
+
+    pub fn with_expiry(mut self, expires_at: Option<SystemTime>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expiry| expiry <= now)
+    }
+
+    /// Shown and hidden shares left on the order
+    pub fn remaining(&self) -> usize {
+        self.quantity + self.hidden
+    }
+
+    pub fn set_remaining(&mut self, remaining: usize) {
+        self.quantity = self.display.map_or(remaining, |d| d.min(remaining));
+        self.hidden = remaining - self.quantity;
+    }
+
+    /// Shows the next slice once the shown one is used up, moving the order to the
+    /// back of the queue. Returns true if it was replenished.
+    pub fn replenish(&mut self, seq: u64) -> bool {
+        if self.quantity > 0 || self.hidden == 0 {
+            return false;
         }
+        self.set_remaining(self.hidden);
+        self.seq = seq;
+        true
     }
 }
 
 impl PartialEq for Ask {
     fn eq(&self, other: &Self) -> bool {
-        self.price == other.price
+        self.price == other.price && self.seq == other.seq
     }
 }
 
@@ -68,6 +197,42 @@ impl Eq for Ask {}
 
 impl Ord for Ask {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.price.cmp(&other.price)
+        self.price
+            .cmp(&other.price)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+/// Order kept off the book until the last trade reaches `trigger`, after which
+/// it is placed as a limit order, or at the edge of the price band if it has no limit
+#[derive(Debug)]
+pub struct StopOrder {
+    pub id: OrderId,
+    pub side: Side,
+    pub trigger: Ticks,
+    pub limit: Option<Ticks>,
+    pub quantity: usize,
+    pub account: AccId,
+    pub seq: u64,
+}
+
+impl StopOrder {
+    pub fn new(
+        acc: AccId,
+        side: Side,
+        trigger: Ticks,
+        limit: Option<Ticks>,
+        quantity: usize,
+        seq: u64,
+    ) -> Self {
+        Self {
+            id: OrderId(Uuid::new_v4()),
+            side,
+            trigger,
+            limit,
+            quantity,
+            account: acc,
+            seq,
+        }
     }
 }
@@ -0,0 +1,210 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::money::Money;
+
+/// Price band and volatility halt settings of a security
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Largest allowed distance of an order price from the reference price, as a fraction
+    pub band: f64,
+    /// Largest allowed price move within `window` before trading halts, as a fraction
+    pub max_move: f64,
+    pub window: Duration,
+    pub cooloff: Duration,
+}
+
+impl BreakerConfig {
+    /// Reason the settings can not be traded under, if any
+    pub fn check(&self) -> Result<(), String> {
+        if !(self.band.is_finite() && self.band > 0.0) {
+            return Err(format!(
+                "Price band {} is not a positive fraction",
+                self.band
+            ));
+        }
+        if !(self.max_move.is_finite() && self.max_move > 0.0) {
+            return Err(format!(
+                "Halt move {} is not a positive fraction",
+                self.max_move
+            ));
+        }
+        if self.window.is_zero() {
+            return Err("Halt window must be longer than zero".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            band: 0.2,
+            max_move: 0.1,
+            window: Duration::from_secs(60),
+            cooloff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Tracks recent trades of a security and halts it when they move too far, too fast
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    pub config: BreakerConfig,
+    recent: VecDeque<(Instant, Money)>,
+    halted_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Lowest and highest order price accepted around `reference`
+    pub fn band(&self, reference: Money) -> (Money, Money) {
+        (
+            reference.scale_by(1.0 - self.config.band),
+            reference.scale_by(1.0 + self.config.band),
+        )
+    }
+
+    pub fn in_band(&self, reference: Money, price: Money) -> bool {
+        let (low, high) = self.band(reference);
+        low <= price && price <= high
+    }
+
+    pub fn halted_until(&self, now: Instant) -> Option<Instant> {
+        self.halted_until.filter(|until| now < *until)
+    }
+
+    pub fn is_halted(&self, now: Instant) -> bool {
+        self.halted_until(now).is_some()
+    }
+
+    /// Lifts an expired halt, starting a fresh window. Returns true if trading resumed
+    pub fn resume_if_due(&mut self, now: Instant) -> bool {
+        match self.halted_until {
+            Some(until) if now >= until => {
+                self.halted_until = None;
+                self.recent.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks whether a trade at `price` would move the price further than allowed
+    /// within the window, halting trading if it would. `reference` is used when no
+    /// trades have happened within the window.
+    pub fn check_trade(&mut self, now: Instant, reference: Money, price: Money) -> bool {
+        while let Some((time, _)) = self.recent.front() {
+            if now.duration_since(*time) > self.config.window {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let base = self.recent.front().map_or(reference, |(_, p)| *p);
+        if !base.is_positive() {
+            return true;
+        }
+
+        if ((price - base).to_f64() / base.to_f64()).abs() > self.config.max_move {
+            self.halted_until = Some(now + self.config.cooloff);
+            false
+        } else {
+            true
+        }
+    }
+
+    pub fn record_trade(&mut self, now: Instant, price: Money) {
+        self.recent.push_back((now, price));
+    }
+
+    /// Scales the prices of recent trades after a split, so it does not look like a move
+    pub fn split(&mut self, price_factor: f64) {
+        for (_, price) in self.recent.iter_mut() {
+            *price = price.scale_by(price_factor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn band_is_a_fraction_either_side_of_the_reference() {
+        let breaker = CircuitBreaker::default();
+        assert_eq!(breaker.band(Money(10_000)), (Money(8_000), Money(12_000)));
+        assert!(breaker.in_band(Money(10_000), Money(8_000)));
+        assert!(breaker.in_band(Money(10_000), Money(12_000)));
+        assert!(!breaker.in_band(Money(10_000), Money(12_001)));
+        assert!(!breaker.in_band(Money(10_000), Money(7_999)));
+    }
+
+    #[test]
+    fn halts_on_a_large_move_until_the_cooloff_ends() {
+        let mut breaker = CircuitBreaker::default();
+        let now = Instant::now();
+        assert!(breaker.check_trade(now, Money(10_000), Money(11_000)));
+        assert!(!breaker.is_halted(now));
+        assert!(!breaker.check_trade(now, Money(10_000), Money(11_001)));
+        assert!(breaker.is_halted(now));
+
+        let cooloff = breaker.config.cooloff;
+        assert!(!breaker.resume_if_due(now + cooloff / 2));
+        assert!(breaker.is_halted(now + cooloff / 2));
+        assert!(breaker.resume_if_due(now + cooloff));
+        assert!(!breaker.is_halted(now + cooloff));
+    }
+
+    #[test]
+    fn moves_are_measured_from_the_oldest_trade_in_the_window() {
+        let mut breaker = CircuitBreaker::default();
+        let now = Instant::now();
+        breaker.record_trade(now, Money(10_000));
+        let later = now + Duration::from_secs(1);
+        assert!(breaker.check_trade(later, Money(20_000), Money(10_500)));
+
+        // Once the trade has left the window the reference is used again
+        let after_window = now + breaker.config.window + Duration::from_secs(1);
+        assert!(!breaker.check_trade(after_window, Money(20_000), Money(10_500)));
+    }
+
+    #[test]
+    fn split_scales_recent_trades() {
+        let mut breaker = CircuitBreaker::default();
+        let now = Instant::now();
+        breaker.record_trade(now, Money(10_000));
+        breaker.split(0.5);
+        assert!(breaker.check_trade(now, Money(5_000), Money(5_000)));
+        assert!(!breaker.check_trade(now, Money(5_000), Money(10_000)));
+    }
+
+    #[test]
+    fn config_must_be_positive() {
+        assert!(BreakerConfig::default().check().is_ok());
+        let config = BreakerConfig {
+            band: 0.0,
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+        let config = BreakerConfig {
+            max_move: f64::NAN,
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+        let config = BreakerConfig {
+            window: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(config.check().is_err());
+    }
+}
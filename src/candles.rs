@@ -0,0 +1,205 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::money::Money;
+
+/// Candles kept for each interval of a security, the oldest are dropped first
+const MAX_CANDLES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandleInterval {
+    OneSecond,
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 5] = [
+        CandleInterval::OneSecond,
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::OneDay,
+    ];
+
+    pub fn duration(self) -> Duration {
+        Duration::from_secs(match self {
+            CandleInterval::OneSecond => 1,
+            CandleInterval::OneMinute => 60,
+            CandleInterval::FiveMinutes => 5 * 60,
+            CandleInterval::OneHour => 60 * 60,
+            CandleInterval::OneDay => 24 * 60 * 60,
+        })
+    }
+
+    /// Start of the candle `time` falls in, aligned to the Unix epoch
+    fn start_of(self, time: SystemTime) -> SystemTime {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let len = self.duration().as_secs();
+        UNIX_EPOCH + Duration::from_secs(secs / len * len)
+    }
+}
+
+/// Open, high, low and close prices and volume of the trades within one interval
+#[derive(Debug, Clone)]
+pub struct Candle {
+    pub interval: CandleInterval,
+    pub start: SystemTime,
+    pub open: Money,
+    pub high: Money,
+    pub low: Money,
+    pub close: Money,
+    /// Shares traded
+    pub volume: usize,
+}
+
+/// Candles of one security at every interval. Intervals without trades have no
+/// candle.
+#[derive(Debug, Default)]
+pub struct CandleSeries {
+    candles: HashMap<CandleInterval, VecDeque<Candle>>,
+}
+
+impl CandleSeries {
+    /// Adds a trade to the current candle of every interval, returning the updated
+    /// candles
+    pub fn record(&mut self, time: SystemTime, price: Money, quantity: usize) -> Vec<Candle> {
+        CandleInterval::ALL
+            .iter()
+            .map(|interval| {
+                let start = interval.start_of(time);
+                let candles = self.candles.entry(*interval).or_default();
+                match candles.back_mut() {
+                    Some(candle) if candle.start == start => {
+                        candle.high = candle.high.max(price);
+                        candle.low = candle.low.min(price);
+                        candle.close = price;
+                        candle.volume += quantity;
+                    }
+                    _ => {
+                        if candles.len() == MAX_CANDLES {
+                            candles.pop_front();
+                        }
+                        candles.push_back(Candle {
+                            interval: *interval,
+                            start,
+                            open: price,
+                            high: price,
+                            low: price,
+                            close: price,
+                            volume: quantity,
+                        });
+                    }
+                }
+                candles.back().unwrap().clone()
+            })
+            .collect()
+    }
+
+    /// Most shares traded within any one candle
+    pub fn max_volume(&self) -> usize {
+        self.candles
+            .values()
+            .flatten()
+            .map(|candle| candle.volume)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Restates every candle as if every `from` shares had been `to` shares all along
+    pub fn split(&mut self, to: usize, from: usize) {
+        let factor = from as f64 / to as f64;
+        for candle in self.candles.values_mut().flatten() {
+            candle.open = candle.open.scale_by(factor);
+            candle.high = candle.high.scale_by(factor);
+            candle.low = candle.low.scale_by(factor);
+            candle.close = candle.close.scale_by(factor);
+            candle.volume = candle.volume * to / from;
+        }
+    }
+
+    /// Candles starting within `from..to`, oldest first
+    pub fn range(
+        &self,
+        interval: CandleInterval,
+        from: Option<SystemTime>,
+        to: Option<SystemTime>,
+    ) -> Vec<Candle> {
+        self.candles
+            .get(&interval)
+            .into_iter()
+            .flatten()
+            .filter(|c| from.is_none_or(|from| c.start >= from))
+            .filter(|c| to.is_none_or(|to| c.start < to))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trades_within_an_interval_share_a_candle() {
+        let mut series = CandleSeries::default();
+        let start = UNIX_EPOCH + Duration::from_secs(120);
+        assert_eq!(series.record(start, Money(100_000), 5).len(), 5);
+        series.record(start + Duration::from_secs(30), Money(120_000), 3);
+        series.record(start + Duration::from_secs(59), Money(90_000), 1);
+
+        let minutes = series.range(CandleInterval::OneMinute, None, None);
+        assert_eq!(minutes.len(), 1);
+        let candle = &minutes[0];
+        assert_eq!(candle.start, start);
+        assert_eq!(
+            (candle.open, candle.high, candle.low, candle.close),
+            (Money(100_000), Money(120_000), Money(90_000), Money(90_000))
+        );
+        assert_eq!(candle.volume, 9);
+        assert_eq!(series.range(CandleInterval::OneSecond, None, None).len(), 3);
+
+        series.record(start + Duration::from_secs(60), Money(95_000), 2);
+        assert_eq!(series.range(CandleInterval::OneMinute, None, None).len(), 2);
+        assert_eq!(series.max_volume(), 11);
+    }
+
+    #[test]
+    fn candles_start_on_interval_boundaries() {
+        let time = UNIX_EPOCH + Duration::from_secs(3 * 60 * 60 + 7 * 60 + 5);
+        assert_eq!(
+            CandleInterval::FiveMinutes.start_of(time),
+            UNIX_EPOCH + Duration::from_secs(3 * 60 * 60 + 5 * 60)
+        );
+        assert_eq!(CandleInterval::OneDay.start_of(time), UNIX_EPOCH);
+    }
+
+    #[test]
+    fn range_includes_from_and_excludes_to() {
+        let mut series = CandleSeries::default();
+        for secs in 0..5 {
+            series.record(UNIX_EPOCH + Duration::from_secs(secs), Money(10_000), 1);
+        }
+        let from = UNIX_EPOCH + Duration::from_secs(1);
+        let to = UNIX_EPOCH + Duration::from_secs(3);
+        let candles = series.range(CandleInterval::OneSecond, Some(from), Some(to));
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].start, from);
+    }
+
+    #[test]
+    fn split_restates_every_candle() {
+        let mut series = CandleSeries::default();
+        series.record(UNIX_EPOCH, Money(100_000), 3);
+        series.split(1, 2);
+        let candle = &series.range(CandleInterval::OneHour, None, None)[0];
+        assert_eq!((candle.open, candle.volume), (Money(200_000), 1));
+    }
+}
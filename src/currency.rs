@@ -0,0 +1,142 @@
+use std::{collections::HashMap, fmt};
+
+use crate::money::Money;
+
+/// Three letter code of a currency, such as USD
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Currency(String);
+
+impl Currency {
+    /// Currency rates are quoted against, and of accounts and securities not given
+    /// another
+    pub const BASE: &'static str = "USD";
+
+    /// Currency with the code `code` in any case, None unless it is three letters
+    pub fn new(code: &str) -> Option<Self> {
+        (code.len() == 3 && code.chars().all(|c| c.is_ascii_alphabetic()))
+            .then(|| Self(code.to_ascii_uppercase()))
+    }
+
+    pub fn code(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self(Self::BASE.to_string())
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Exchange rates set by the exchange, each the units of the base currency one
+/// unit of a currency is worth
+#[derive(Debug, Clone, Default)]
+pub struct FxRates {
+    rates: HashMap<Currency, f64>,
+}
+
+impl FxRates {
+    pub fn set(&mut self, currency: Currency, rate: f64) {
+        self.rates.insert(currency, rate);
+    }
+
+    /// Rate of `currency`, always 1 for the base currency
+    pub fn rate(&self, currency: &Currency) -> Option<f64> {
+        if currency.code() == Currency::BASE {
+            return Some(1.0);
+        }
+        self.rates.get(currency).copied()
+    }
+
+    /// Every rate set, the base currency left out
+    pub fn rates(&self) -> impl Iterator<Item = (&Currency, f64)> {
+        self.rates.iter().map(|(currency, rate)| (currency, *rate))
+    }
+
+    /// `amount` of `from` converted to `to`, rounded to a minor unit. None when
+    /// either has no rate.
+    pub fn convert(&self, amount: Money, from: &Currency, to: &Currency) -> Option<Money> {
+        if from == to {
+            return Some(amount);
+        }
+        Some(amount.scale_by(self.rate(from)? / self.rate(to)?))
+    }
+
+    /// `amount` of `from` exchanged for `to`, rounded down to a minor unit so that
+    /// exchanging back and forth never gains anything. None when either has no rate.
+    pub fn exchange(&self, amount: Money, from: &Currency, to: &Currency) -> Option<Money> {
+        if from == to {
+            return Some(amount);
+        }
+        Some(amount.scale_down(self.rate(from)? / self.rate(to)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rates() -> (FxRates, Currency, Currency) {
+        let eur = Currency::new("eur").unwrap();
+        let mut rates = FxRates::default();
+        rates.set(eur.clone(), 1.1);
+        (rates, eur, Currency::default())
+    }
+
+    #[test]
+    fn codes_are_three_letters() {
+        assert_eq!(Currency::new("eur").unwrap().code(), "EUR");
+        assert_eq!(Currency::new("US"), None);
+        assert_eq!(Currency::new("U5D"), None);
+        assert_eq!(Currency::new("USDX"), None);
+        assert_eq!(Currency::default().code(), Currency::BASE);
+    }
+
+    #[test]
+    fn convert_rounds_to_nearest_and_exchange_rounds_down() {
+        let (rates, eur, usd) = rates();
+        assert_eq!(
+            rates.convert(Money(10_000), &eur, &usd),
+            Some(Money(11_000))
+        );
+        assert_eq!(
+            rates.convert(Money(11_005), &usd, &eur),
+            Some(Money(10_005))
+        );
+        assert_eq!(
+            rates.exchange(Money(11_005), &usd, &eur),
+            Some(Money(10_004))
+        );
+        assert_eq!(rates.convert(Money(1), &usd, &eur), Some(Money(1)));
+        assert_eq!(rates.exchange(Money(1), &usd, &eur), Some(Money(0)));
+    }
+
+    #[test]
+    fn exchanging_back_and_forth_never_gains() {
+        let (rates, eur, usd) = rates();
+        for amount in (1..10_000).map(Money) {
+            let there = rates.exchange(amount, &eur, &usd).unwrap();
+            let back = rates.exchange(there, &usd, &eur).unwrap();
+            assert!(back <= amount, "{} came back as {}", amount, back);
+        }
+    }
+
+    #[test]
+    fn missing_rates_can_not_be_converted() {
+        let (rates, eur, usd) = rates();
+        let gbp = Currency::new("GBP").unwrap();
+        assert_eq!(rates.convert(Money(10_000), &gbp, &usd), None);
+        assert_eq!(rates.exchange(Money(10_000), &eur, &gbp), None);
+        assert_eq!(
+            rates.convert(Money(10_000), &gbp, &gbp),
+            Some(Money(10_000))
+        );
+        assert_eq!(rates.rate(&usd), Some(1.0));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::SystemTime;
+
+use crate::{
+    bidask::{Ask, Bid, Side, StopOrder},
+    money::Money,
+    AccId, OrderId, SecId,
+};
+
+/// What happened to an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecKind {
+    Fill,
+    /// Canceled to stop the account trading with itself
+    SelfTradeCanceled,
+    /// Reduced by the size of an opposing order of the same account
+    SelfTradeDecremented,
+    /// Stop order placed on the book after its trigger price was reached
+    StopTriggered,
+    /// Next slice of an iceberg order shown, losing time priority
+    Replenished,
+    /// Removed from the book after its time in force ran out
+    Expired,
+    /// Placed to buy back shares of a short position over its limit
+    BuyIn,
+    /// Placed to close a position of an account that fell below its maintenance margin
+    Liquidation,
+    /// Restated by a split, `quantity` being the shares open before it. Orders left
+    /// with no shares are removed.
+    Adjusted,
+    /// Canceled as the security was delisted
+    Delisted,
+    /// Canceled as trading it at the price of the order it met was worth too much
+    /// to hold
+    Overflowed,
+    /// Canceled by the account that placed it
+    Canceled,
+    /// Stop canceled as its limit price was no longer valid when it triggered, or ask
+    /// canceled as the account had no shares left to sell when it met a bid
+    Rejected,
+    /// Placed for the account by a corporate action, such as an issue or buyback
+    Placed,
+}
+
+/// Report of something happening to an order, sent to the account that placed it
+#[derive(Debug, Clone)]
+pub struct Execution {
+    pub account: AccId,
+    pub sec: SecId,
+    pub order: OrderId,
+    pub side: Side,
+    pub kind: ExecKind,
+    pub price: Money,
+    /// Number of shares affected
+    pub quantity: usize,
+    /// Number of shares still open on the order
+    pub remaining: usize,
+    /// Charged on fills
+    pub fee: Money,
+}
+
+/// Shares changing hands between a buyer and a seller
+#[derive(Debug, Clone)]
+pub struct Trade {
+    pub time: SystemTime,
+    pub price: Money,
+    pub quantity: usize,
+    pub buyer: AccId,
+    pub seller: AccId,
+    pub bid: OrderId,
+    pub ask: OrderId,
+    /// Side of the order that traded with the one resting on the book
+    pub taker: Side,
+    pub buyer_fee: Money,
+    pub seller_fee: Money,
+}
+
+impl Execution {
+    pub fn of_bid(sec: SecId, bid: &Bid, kind: ExecKind, price: Money, quantity: usize) -> Self {
+        Self {
+            account: bid.account,
+            sec,
+            order: bid.id,
+            side: Side::Buy,
+            kind,
+            price,
+            quantity,
+            remaining: bid.remaining(),
+            fee: Money::ZERO,
+        }
+    }
+
+    pub fn of_ask(sec: SecId, ask: &Ask, kind: ExecKind, price: Money, quantity: usize) -> Self {
+        Self {
+            account: ask.account,
+            sec,
+            order: ask.id,
+            side: Side::Sell,
+            kind,
+            price,
+            quantity,
+            remaining: ask.remaining(),
+            fee: Money::ZERO,
+        }
+    }
+
+    pub fn of_stop(
+        sec: SecId,
+        stop: &StopOrder,
+        kind: ExecKind,
+        price: Money,
+        quantity: usize,
+    ) -> Self {
+        Self {
+            account: stop.account,
+            sec,
+            order: stop.id,
+            side: stop.side,
+            kind,
+            price,
+            quantity,
+            remaining: stop.quantity,
+            fee: Money::ZERO,
+        }
+    }
+}
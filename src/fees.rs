@@ -0,0 +1,85 @@
+use crate::money::Money;
+
+/// How the fee on a fill is worked out from its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeRate {
+    /// Same amount on every fill
+    Flat(Money),
+    /// Hundredths of a percent of the value
+    BasisPoints(i64),
+}
+
+impl Default for FeeRate {
+    fn default() -> Self {
+        FeeRate::BasisPoints(0)
+    }
+}
+
+impl FeeRate {
+    /// Fee on a fill worth `notional`, rounded towards zero
+    pub fn fee(self, notional: Money) -> Money {
+        match self {
+            FeeRate::Flat(fee) => fee,
+            FeeRate::BasisPoints(bps) => {
+                Money((notional.minor_units() as i128 * bps as i128 / 10_000) as i64)
+            }
+        }
+    }
+}
+
+/// Fees charged to the order that was resting on the book and to the order that
+/// traded with it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub maker: FeeRate,
+    pub taker: FeeRate,
+}
+
+impl FeeSchedule {
+    pub fn fee(&self, maker: bool, notional: Money) -> Money {
+        if maker {
+            self.maker.fee(notional)
+        } else {
+            self.taker.fee(notional)
+        }
+    }
+
+    /// Most that either side of a fill worth `notional` could be charged
+    pub fn max_fee(&self, notional: Money) -> Money {
+        self.maker.fee(notional).max(self.taker.fee(notional))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basis_points_round_towards_zero() {
+        let rate = FeeRate::BasisPoints(25);
+        assert_eq!(rate.fee(Money(1_000_000)), Money(2_500));
+        assert_eq!(rate.fee(Money(399)), Money(0));
+        assert_eq!(rate.fee(Money(-1_000_000)), Money(-2_500));
+        assert_eq!(FeeRate::BasisPoints(10_000).fee(Money::MAX), Money::MAX);
+    }
+
+    #[test]
+    fn flat_fee_ignores_value() {
+        let rate = FeeRate::Flat(Money(10_000));
+        assert_eq!(rate.fee(Money(1)), Money(10_000));
+        assert_eq!(rate.fee(Money(1_000_000_000)), Money(10_000));
+        assert_eq!(FeeRate::default().fee(Money(1_000_000)), Money::ZERO);
+    }
+
+    #[test]
+    fn schedule_charges_maker_and_taker_their_own_rate() {
+        let schedule = FeeSchedule {
+            maker: FeeRate::BasisPoints(10),
+            taker: FeeRate::Flat(Money(500)),
+        };
+        assert_eq!(schedule.fee(true, Money(1_000_000)), Money(1_000));
+        assert_eq!(schedule.fee(false, Money(1_000_000)), Money(500));
+        assert_eq!(schedule.max_fee(Money(1_000_000)), Money(1_000));
+        assert_eq!(schedule.max_fee(Money(10_000)), Money(500));
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::SystemTime;
+
+use crate::{money::Money, SecId};
+
+/// What a futures series is settled against at expiry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Underlying {
+    /// Last trade of a listed security
+    Security(SecId),
+    /// Value of an index, by name
+    Index(String),
+}
+
+/// Terms of a cash-settled futures series, listed as a security of its own. Prices
+/// are quoted in units of the underlying, and positions are marked to the
+/// settlement price every session, the difference paid in cash.
+#[derive(Debug, Clone)]
+pub struct FutureContract {
+    pub underlying: Underlying,
+    pub expiry: SystemTime,
+    /// Units of the underlying each contract is for
+    pub multiplier: usize,
+    /// Cash an account must hold for each contract it is long or short to add to
+    /// its positions
+    pub initial_margin: Money,
+    /// Cash an account must keep for each contract, below which its positions are
+    /// liquidated
+    pub maintenance_margin: Money,
+}
+
+impl FutureContract {
+    /// Cash paid to a position of `contracts` as the price moves from `from` to `to`,
+    /// negative when paid by it. None if it can not be held.
+    pub fn variation(&self, contracts: i64, from: Money, to: Money) -> Option<Money> {
+        let units = contracts.checked_mul(i64::try_from(self.multiplier).ok()?)?;
+        to.checked_sub(from)?.checked_mul(units)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract(multiplier: usize) -> FutureContract {
+        FutureContract {
+            underlying: Underlying::Index("STOK".to_string()),
+            expiry: SystemTime::now(),
+            multiplier,
+            initial_margin: Money(1_000_000),
+            maintenance_margin: Money(500_000),
+        }
+    }
+
+    #[test]
+    fn variation_pays_longs_when_the_price_rises() {
+        let future = contract(10);
+        assert_eq!(
+            future.variation(2, Money(1_000_000), Money(1_015_000)),
+            Some(Money(300_000))
+        );
+        assert_eq!(
+            future.variation(-2, Money(1_000_000), Money(1_015_000)),
+            Some(Money(-300_000))
+        );
+        assert_eq!(
+            future.variation(3, Money(1_000_000), Money(990_000)),
+            Some(Money(-300_000))
+        );
+        assert_eq!(
+            future.variation(0, Money(1_000_000), Money(2_000_000)),
+            Some(Money::ZERO)
+        );
+    }
+
+    #[test]
+    fn variation_too_large_to_hold_is_none() {
+        assert_eq!(contract(10).variation(i64::MAX, Money(0), Money(1)), None);
+        assert_eq!(contract(1).variation(1, Money(i64::MIN), Money::MAX), None);
+        assert_eq!(contract(usize::MAX).variation(1, Money(0), Money(1)), None);
+    }
+}
@@ -0,0 +1,284 @@
+use std::{collections::HashMap, env};
+
+use log::warn;
+
+use crate::{money::Money, SecId};
+
+/// How much each member counts towards an index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weighting {
+    /// By price times shares outstanding
+    MarketCap,
+    /// By price alone
+    Price,
+    /// By the change in price since joining, each member counting the same
+    Equal,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub name: String,
+    pub weighting: Weighting,
+    /// Securities included, every listed security when empty
+    pub members: Vec<SecId>,
+    /// Value the index starts at
+    pub base_value: f64,
+}
+
+impl IndexConfig {
+    /// Reads indices over every listed security from `STOK_INDICES`, a `;` separated
+    /// list of `name:weighting` with an optional `:base_value`, the weighting one of
+    /// `market_cap`, `price` or `equal`. Invalid entries are skipped, and one index
+    /// of each weighting is kept when it is unset.
+    pub fn from_env() -> Vec<Self> {
+        let Ok(value) = env::var("STOK_INDICES") else {
+            return Self::defaults();
+        };
+        value
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let config = Self::parse(entry);
+                if config.is_none() {
+                    warn!("Ignoring invalid index {:?} of STOK_INDICES", entry);
+                }
+                config
+            })
+            .collect()
+    }
+
+    fn parse(entry: &str) -> Option<Self> {
+        let mut parts = entry.split(':').map(str::trim);
+        let name = parts.next().filter(|name| !name.is_empty())?;
+        let weighting = match parts.next()? {
+            "market_cap" => Weighting::MarketCap,
+            "price" => Weighting::Price,
+            "equal" => Weighting::Equal,
+            _ => return None,
+        };
+        let base_value = match parts.next() {
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|value: &f64| value.is_finite() && *value > 0.0)?,
+            None => 1000.0,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            name: name.to_string(),
+            weighting,
+            members: Vec::new(),
+            base_value,
+        })
+    }
+
+    /// Indices over every listed security, one for each weighting
+    fn defaults() -> Vec<Self> {
+        [
+            ("STOK", Weighting::MarketCap),
+            ("STOK-PW", Weighting::Price),
+            ("STOK-EW", Weighting::Equal),
+        ]
+        .into_iter()
+        .map(|(name, weighting)| Self {
+            name: name.to_string(),
+            weighting,
+            members: Vec::new(),
+            base_value: 1000.0,
+        })
+        .collect()
+    }
+}
+
+/// Price and shares outstanding of a listed security
+#[derive(Debug, Clone, Copy)]
+pub struct Quote {
+    pub price: Money,
+    pub shares_outstanding: usize,
+}
+
+/// Index over the prices of listed securities. The divisor is adjusted whenever
+/// members join or leave so that the value only moves with prices.
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub config: IndexConfig,
+    /// Price of each member when it joined
+    members: HashMap<SecId, Money>,
+    divisor: f64,
+    pub value: f64,
+}
+
+impl Index {
+    pub fn new(config: IndexConfig) -> Self {
+        Self {
+            value: config.base_value,
+            config,
+            members: HashMap::new(),
+            divisor: 0.0,
+        }
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &SecId> {
+        self.members.keys()
+    }
+
+    fn raw(&self, quotes: &HashMap<SecId, Quote>) -> f64 {
+        self.members
+            .iter()
+            .filter_map(|(sec_id, joined_at)| {
+                let quote = quotes.get(sec_id)?;
+                Some(match self.config.weighting {
+                    Weighting::MarketCap => quote.price.to_f64() * quote.shares_outstanding as f64,
+                    Weighting::Price => quote.price.to_f64(),
+                    Weighting::Equal if joined_at.is_positive() => {
+                        quote.price.to_f64() / joined_at.to_f64()
+                    }
+                    Weighting::Equal => 0.0,
+                })
+            })
+            .sum()
+    }
+
+    /// Recalculates the value from the quotes of every listed security, then takes
+    /// in newly listed members and drops delisted ones. Returns true if the value
+    /// changed.
+    pub fn update(&mut self, quotes: &HashMap<SecId, Quote>) -> bool {
+        let before = self.value;
+        let eligible: HashMap<_, _> = quotes
+            .iter()
+            .filter(|(sec_id, _)| {
+                self.config.members.is_empty() || self.config.members.contains(sec_id)
+            })
+            .collect();
+        let joined = eligible
+            .keys()
+            .any(|sec_id| !self.members.contains_key(sec_id));
+        let left = self
+            .members
+            .keys()
+            .any(|sec_id| !eligible.contains_key(sec_id));
+
+        // Members that left have no quote, so the value is kept as it was when they did
+        if self.divisor > 0.0 && !left {
+            self.value = self.raw(quotes) / self.divisor;
+        }
+        if joined || left {
+            self.members
+                .retain(|sec_id, _| eligible.contains_key(sec_id));
+            for (sec_id, quote) in eligible {
+                self.members.entry(*sec_id).or_insert(quote.price);
+            }
+            self.rebase(quotes);
+        }
+
+        self.value != before
+    }
+
+    /// Restates the joining price of a member whose price was scaled by a split, and
+    /// adjusts the divisor so the split does not move the value
+    pub fn split(&mut self, sec: SecId, price_factor: f64, quotes: &HashMap<SecId, Quote>) {
+        let Some(joined_at) = self.members.get_mut(&sec) else {
+            return;
+        };
+        *joined_at = joined_at.scale_by(price_factor);
+        self.rebase(quotes);
+    }
+
+    /// Adjusts the divisor so the value stays the same at `quotes`, after shares
+    /// outstanding changed without trading
+    pub fn rebase(&mut self, quotes: &HashMap<SecId, Quote>) {
+        let raw = self.raw(quotes);
+        self.divisor = if raw > 0.0 { raw / self.value } else { 0.0 };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn quote(price: i64, shares_outstanding: usize) -> Quote {
+        Quote {
+            price: Money(price),
+            shares_outstanding,
+        }
+    }
+
+    fn weighted(weighting: Weighting) -> Index {
+        Index::new(IndexConfig {
+            name: "TEST".to_string(),
+            weighting,
+            members: Vec::new(),
+            base_value: 1000.0,
+        })
+    }
+
+    #[test]
+    fn parses_entries() {
+        let config = IndexConfig::parse("TECH : equal : 500").unwrap();
+        assert_eq!(config.name, "TECH");
+        assert_eq!(config.weighting, Weighting::Equal);
+        assert_eq!(config.base_value, 500.0);
+        assert_eq!(IndexConfig::parse("ALL:price").unwrap().base_value, 1000.0);
+        assert!(IndexConfig::parse("ALL:bad").is_none());
+        assert!(IndexConfig::parse(":price").is_none());
+        assert!(IndexConfig::parse("ALL:price:0").is_none());
+        assert!(IndexConfig::parse("ALL:price:inf").is_none());
+        assert!(IndexConfig::parse("ALL:price:100:more").is_none());
+    }
+
+    #[test]
+    fn value_moves_with_prices_only() {
+        let [a, b] = [(); 2].map(|_| SecId(Uuid::new_v4()));
+        let mut index = weighted(Weighting::MarketCap);
+        let mut quotes = HashMap::from([(a, quote(100_000, 100)), (b, quote(200_000, 50))]);
+        assert!(!index.update(&quotes));
+        assert_eq!(index.value, 1000.0);
+
+        quotes.insert(a, quote(120_000, 100));
+        assert!(index.update(&quotes));
+        assert!((index.value - 1100.0).abs() < 1e-9);
+
+        // Leaving keeps the value, which then moves with the members left
+        quotes.remove(&b);
+        index.update(&quotes);
+        assert!((index.value - 1100.0).abs() < 1e-9);
+        quotes.insert(a, quote(60_000, 100));
+        index.update(&quotes);
+        assert!((index.value - 550.0).abs() < 1e-9);
+        assert_eq!(index.members().collect::<Vec<_>>(), vec![&a]);
+    }
+
+    #[test]
+    fn equal_weighting_counts_each_change_the_same() {
+        let [a, b] = [(); 2].map(|_| SecId(Uuid::new_v4()));
+        let mut index = weighted(Weighting::Equal);
+        let mut quotes = HashMap::from([(a, quote(100_000, 1)), (b, quote(500_000, 1_000))]);
+        index.update(&quotes);
+        quotes.insert(a, quote(150_000, 1));
+        index.update(&quotes);
+        assert!((index.value - 1250.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn split_and_rebase_keep_the_value() {
+        let a = SecId(Uuid::new_v4());
+        let mut index = weighted(Weighting::Price);
+        let mut quotes = HashMap::from([(a, quote(100_000, 100))]);
+        index.update(&quotes);
+        quotes.insert(a, quote(50_000, 200));
+        index.split(a, 0.5, &quotes);
+        assert!(!index.update(&quotes));
+        assert_eq!(index.value, 1000.0);
+
+        let mut by_cap = weighted(Weighting::MarketCap);
+        by_cap.update(&quotes);
+        quotes.insert(a, quote(50_000, 300));
+        by_cap.rebase(&quotes);
+        assert!(!by_cap.update(&quotes));
+    }
+}
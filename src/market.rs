@@ -1,49 +1,376 @@
 use std::{
-    collections::{BinaryHeap, HashMap},
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
     fmt::format,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use dashmap::DashMap;
 use log::{debug, error, info, trace, warn};
-use ordered_float::NotNan;
 use thiserror::Error;
-use tokio::sync::watch::Receiver;
+use tokio::sync::{broadcast, watch::Receiver};
 use tonic::Status;
 use uuid::Uuid;
 
 use crate::{
-    bidask::{Ask, Bid},
-    AccId, SecId,
+    account::{Account, EntryKind, Margin, MarginCall, StatementEntry},
+    actions::{ActionKind, CorporateAction, Dividend},
+    basket::Basket,
+    bidask::{Ask, Bid, Side, StopOrder, Ticks, TimeInForce},
+    breaker::{BreakerConfig, CircuitBreaker},
+    candles::{Candle, CandleInterval, CandleSeries},
+    currency::{Currency, FxRates},
+    execution::{ExecKind, Execution, Trade},
+    fees::FeeSchedule,
+    futures::{FutureContract, Underlying},
+    index::{Index, IndexConfig, Quote},
+    money::Money,
+    options::{OptionContract, OptionKind},
+    risk::{OrderRequest, RiskCheck, RiskContext},
+    stats::SessionStats,
+    AccId, OrderId, SecId,
 };
 
+/// Trades kept for each security, the oldest are dropped first
+const MAX_TRADES: usize = 10_000;
+
+/// Most shares a split may turn one into, or a consolidation may turn into one
+const MAX_SPLIT_RATIO: usize = 1_000;
+
 #[derive(Debug, Clone)]
 pub struct Market {
     securities: Arc<DashMap<SecId, Security>>,
-    accounts: Arc<DashMap<AccId, HashMap<SecId, usize>>>,
+    accounts: Arc<DashMap<AccId, Account>>,
+    /// Hands out time priority to orders
+    sequence: Arc<AtomicU64>,
+    executions: broadcast::Sender<Execution>,
+    margin_calls: broadcast::Sender<MarginCall>,
+    /// Candles updated by each trade
+    candles: broadcast::Sender<(SecId, Candle)>,
+    indices: Arc<DashMap<String, Index>>,
+    /// Index names and values, sent whenever they change
+    index_values: broadcast::Sender<(String, f64)>,
+    corporate_actions: broadcast::Sender<CorporateAction>,
+    /// Entries posted to account statements
+    statements: broadcast::Sender<(AccId, StatementEntry)>,
+    /// Only read through `rates`, so it is never held while waiting on a security or
+    /// account
+    fx_rates: Arc<RwLock<FxRates>>,
+    /// Collects trading fees
+    fee_account: AccId,
+    /// Time of day, in UTC, at which day orders expire
+    session_close: Duration,
+    /// Run in order before an order is accepted
+    risk_checks: Arc<Vec<Box<dyn RiskCheck>>>,
     pub update_reciever: Receiver<()>,
 }
 
 impl Market {
-    pub fn new(mut update_reciever: Receiver<()>) -> Self {
+    pub fn new(
+        mut update_reciever: Receiver<()>,
+        session_close: Duration,
+        risk_checks: Vec<Box<dyn RiskCheck>>,
+        indices: Vec<IndexConfig>,
+    ) -> Self {
         let securities: Arc<DashMap<SecId, Security>> = Default::default();
-        let accounts: Arc<DashMap<AccId, HashMap<SecId, usize>>> = Default::default();
+        let accounts: Arc<DashMap<AccId, Account>> = Default::default();
+
+        let (executions, _) = broadcast::channel(1024);
+        let (margin_calls, _) = broadcast::channel(256);
+        let (candles, _) = broadcast::channel(1024);
+        let (index_values, _) = broadcast::channel(256);
+        let (corporate_actions, _) = broadcast::channel(256);
+        let (statements, _) = broadcast::channel(1024);
+        let indices = indices
+            .into_iter()
+            .map(|config| (config.name.clone(), Index::new(config)))
+            .collect();
+        let fee_account = AccId(Uuid::new_v4());
+        accounts.insert(fee_account, Default::default());
 
         Self {
             securities,
             accounts,
+            sequence: Default::default(),
+            executions,
+            margin_calls,
+            candles,
+            indices: Arc::new(indices),
+            index_values,
+            corporate_actions,
+            statements,
+            fx_rates: Default::default(),
+            fee_account,
+            session_close,
+            risk_checks: Arc::new(risk_checks),
             update_reciever,
         }
     }
 
-    pub fn get_lowest_bid_price(&self, sec_id: SecId) -> Result<Option<f64>, MarketError> {
+    /// The next time day orders expire after `now`
+    fn next_session_close(&self, now: SystemTime) -> SystemTime {
+        session_close_after(self.session_close, now)
+    }
+
+    fn expiry(
+        &self,
+        sec_id: SecId,
+        time_in_force: TimeInForce,
+    ) -> Result<Option<SystemTime>, MarketError> {
+        let now = SystemTime::now();
+        match time_in_force {
+            TimeInForce::GoodTillCancel => Ok(None),
+            TimeInForce::Day => Ok(Some(self.next_session_close(now))),
+            TimeInForce::GoodTillTime(expiry) if expiry > now => Ok(Some(expiry)),
+            TimeInForce::GoodTillTime(expiry) => {
+                error!(
+                    "Rejected order for security {} expiring in the past at {:?}",
+                    sec_id.0, expiry
+                );
+                Err(MarketError::ExpiryInPast(expiry))
+            }
+        }
+    }
+
+    pub fn has_account(&self, acc_id: AccId) -> bool {
+        self.accounts.contains_key(&acc_id)
+    }
+
+    /// Shares in a lot of the security, which orders are a whole number of
+    pub fn lot_size(&self, sec_id: SecId) -> Result<usize, MarketError> {
+        match self.securities.get(&sec_id) {
+            Some(sec) => Ok(sec.lot_size),
+            None => {
+                error!(
+                    "Attempted to look up lot size of nonexistent security {}",
+                    sec_id.0
+                );
+                Err(MarketError::SecDoesNotExist(sec_id))
+            }
+        }
+    }
+
+    pub fn subscribe_executions(&self) -> broadcast::Receiver<Execution> {
+        self.executions.subscribe()
+    }
+
+    pub fn fee_account(&self) -> AccId {
+        self.fee_account
+    }
+
+    /// Snapshot of the exchange rates
+    pub fn rates(&self) -> FxRates {
+        self.fx_rates.read().unwrap().clone()
+    }
+
+    /// Sets the units of the base currency one unit of `currency` is worth, then
+    /// revalues the indices
+    pub fn set_fx_rate(&self, currency: Currency, rate: f64) -> Result<FxRates, MarketError> {
+        if currency.code() == Currency::BASE || !(rate.is_finite() && rate > 0.0) {
+            error!("Rejected exchange rate {} for {}", rate, currency);
+            return Err(MarketError::InvalidFxRate { currency, rate });
+        }
+        info!("Exchange rate of {} set to {}", currency, rate);
+        self.fx_rates.write().unwrap().set(currency, rate);
+        self.recalculate_indices();
+        // Positions in other currencies were just revalued
+        self.liquidate_undermargined();
+        Ok(self.rates())
+    }
+
+    /// `amount` of `from` converted to `to` at the current rates
+    fn convert(&self, amount: Money, from: &Currency, to: &Currency) -> Result<Money, MarketError> {
+        self.rates().convert(amount, from, to).ok_or_else(|| {
+            error!("No exchange rate to convert {} to {}", from, to);
+            MarketError::NoFxRate {
+                from: from.clone(),
+                to: to.clone(),
+            }
+        })
+    }
+
+    /// Exchanges `amount` of an account's cash in `from` for `to` at the current
+    /// rates, rounding what it gets down. Returns the balances left in both.
+    pub fn convert_cash(
+        &self,
+        acc_id: AccId,
+        from: Currency,
+        to: Currency,
+        amount: Money,
+    ) -> Result<(Money, Money), MarketError> {
+        if !amount.is_positive() {
+            error!("Rejected conversion of {} {} to {}", amount, from, to);
+            return Err(MarketError::InvalidAmount(amount));
+        }
+        let Some(converted) = self.rates().exchange(amount, &from, &to) else {
+            error!("No exchange rate to convert {} to {}", from, to);
+            return Err(MarketError::NoFxRate { from, to });
+        };
+        if !converted.is_positive() {
+            error!(
+                "Rejected conversion of {} {} to {} as it buys nothing",
+                amount, from, to
+            );
+            return Err(MarketError::InvalidAmount(amount));
+        }
+        let Some(mut account) = self.accounts.get_mut(&acc_id) else {
+            error!(
+                "Attempted to convert cash of nonexistent account {}",
+                acc_id.0
+            );
+            return Err(MarketError::AccDoesNotExist(acc_id));
+        };
+        let held = account.cash(&from);
+        if held < amount {
+            error!(
+                "Account {} with {} {} attempted to convert {} to {}",
+                acc_id.0, held, from, amount, to
+            );
+            return Err(MarketError::InsufficientCash {
+                acc: acc_id,
+                cash: held,
+                required: amount,
+            });
+        }
+        if account.cash(&to).checked_add(converted).is_none() {
+            error!(
+                "Conversion of {} {} to {} {} is more than account {} can hold",
+                amount, from, converted, to, acc_id.0
+            );
+            return Err(MarketError::InvalidAmount(amount));
+        }
+        let now = SystemTime::now();
+        let kind = EntryKind::Conversion;
+        let sold = account.post(kind, &from, -amount, now);
+        let bought = account.post(kind, &to, converted, now);
+        info!(
+            "Account {} converted {} {} to {} {}",
+            acc_id.0, amount, from, converted, to
+        );
+        let balances = (sold.balance, bought.balance);
+        let _ = self.statements.send((acc_id, sold));
+        let _ = self.statements.send((acc_id, bought));
+        Ok(balances)
+    }
+
+    /// Cash in every currency and the value of every position at its last trade,
+    /// converted to the account's currency. Futures are left out, as their value is
+    /// settled into cash. Returns the currency and the value.
+    pub fn portfolio_value(&self, acc_id: AccId) -> Result<(Currency, Money), MarketError> {
+        let Some((currency, cash, holdings)) = self.accounts.get(&acc_id).map(|account| {
+            let mut cash = account.balances.clone();
+            cash.insert(account.currency.clone(), account.cash);
+            (account.currency.clone(), cash, account.holdings.clone())
+        }) else {
+            error!(
+                "Attempted to value portfolio of nonexistent account {}",
+                acc_id.0
+            );
+            return Err(MarketError::AccDoesNotExist(acc_id));
+        };
+        let mut value = Money::ZERO;
+        for (held, amount) in cash {
+            value = value.saturating_add(self.convert(amount, &held, &currency)?);
+        }
+        for (sec_id, shares) in holdings.into_iter().filter(|(_, shares)| *shares != 0) {
+            let Some((price, held)) = self
+                .securities
+                .get(&sec_id)
+                .filter(|sec| sec.future.is_none())
+                .map(|sec| (sec.last_trade, sec.currency.clone()))
+            else {
+                continue;
+            };
+            let position = price.checked_mul(shares);
+            let total = match position {
+                Some(position) => value.checked_add(self.convert(position, &held, &currency)?),
+                None => None,
+            };
+            let Some(total) = total else {
+                error!(
+                    "Portfolio of account {} is worth too much to value with {} shares of security {}",
+                    acc_id.0, shares, sec_id.0
+                );
+                return Err(MarketError::ValueOverflow {
+                    sec: sec_id,
+                    price,
+                    quantity: shares.unsigned_abs() as usize,
+                });
+            };
+            value = total;
+        }
+        debug!(
+            "Portfolio of account {} is worth {} {}",
+            acc_id.0, value, currency
+        );
+        Ok((currency, value))
+    }
+
+    pub fn subscribe_indices(&self) -> broadcast::Receiver<(String, f64)> {
+        self.index_values.subscribe()
+    }
+
+    pub fn list_indices(&self) -> Vec<String> {
+        self.indices.iter().map(|i| i.key().clone()).collect()
+    }
+
+    pub fn index(&self, name: &str) -> Result<Index, MarketError> {
+        if let Some(index) = self.indices.get(name) {
+            debug!("Index {} is at {}", name, index.value);
+            Ok(index.clone())
+        } else {
+            error!("Attempted to look up nonexistent index {}", name);
+            Err(MarketError::IndexDoesNotExist(name.to_string()))
+        }
+    }
+
+    /// Brings every index up to date with the listed securities
+    fn recalculate_indices(&self) {
+        let quotes = quotes(&self.securities, &self.rates());
+        update_indices(&quotes, &self.indices, &self.index_values);
+    }
+
+    /// Keeps every index at its value after shares outstanding changed without trading
+    fn rebase_indices(&self) {
+        let quotes = quotes(&self.securities, &self.rates());
+        for mut index in self.indices.iter_mut() {
+            index.rebase(&quotes);
+        }
+    }
+
+    pub fn subscribe_candles(&self) -> broadcast::Receiver<(SecId, Candle)> {
+        self.candles.subscribe()
+    }
+
+    pub fn subscribe_corporate_actions(&self) -> broadcast::Receiver<CorporateAction> {
+        self.corporate_actions.subscribe()
+    }
+
+    pub fn subscribe_statements(&self) -> broadcast::Receiver<(AccId, StatementEntry)> {
+        self.statements.subscribe()
+    }
+
+    pub fn subscribe_margin_calls(&self) -> broadcast::Receiver<MarginCall> {
+        self.margin_calls.subscribe()
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn get_lowest_bid_price(&self, sec_id: SecId) -> Result<Option<Money>, MarketError> {
         if let Some(sec) = self.securities.get(&sec_id) {
             if let Some(bid) = sec.bids.peek() {
+                let price = sec.ticks_to_price(bid.price.0);
                 debug!(
                     "Current lowest bid price for security {} is {}",
-                    sec_id.0, bid.price.0
+                    sec_id.0, price
                 );
-                Ok(Some(*bid.price.0))
+                Ok(Some(price))
             } else {
                 debug!("No bids placed for security {}", sec_id.0);
                 Ok(None)
@@ -57,14 +384,15 @@ impl Market {
         }
     }
 
-    pub fn get_highest_ask_price(&self, sec_id: SecId) -> Result<Option<f64>, MarketError> {
+    pub fn get_highest_ask_price(&self, sec_id: SecId) -> Result<Option<Money>, MarketError> {
         if let Some(sec) = self.securities.get(&sec_id) {
             if let Some(ask) = sec.asks.peek() {
+                let price = sec.ticks_to_price(ask.price);
                 debug!(
                     "Current highest ask price for security {} is {}",
-                    sec_id.0, ask.price
+                    sec_id.0, price
                 );
-                Ok(Some(*ask.price))
+                Ok(Some(price))
             } else {
                 debug!("No asks placed for security {}", sec_id.0);
                 Ok(None)
@@ -78,7 +406,7 @@ impl Market {
         }
     }
 
-    pub fn current_value(&self, sec_id: SecId) -> Result<f64, MarketError> {
+    pub fn current_value(&self, sec_id: SecId) -> Result<Money, MarketError> {
         if let Some(sec) = self.securities.get(&sec_id) {
             let price = sec.last_trade;
             debug!(
@@ -95,15 +423,83 @@ impl Market {
         }
     }
 
-    pub fn market_cap(&self, sec_id: SecId) -> Result<f64, MarketError> {
+    /// Shown shares at each price, in the order the matcher takes them. Hidden shares
+    /// of iceberg orders are left out.
+    pub fn depth(&self, sec_id: SecId) -> Result<(Vec<DepthLevel>, Vec<DepthLevel>), MarketError> {
         if let Some(sec) = self.securities.get(&sec_id) {
-            let price = sec.last_trade;
-            let mcap = self
-                .accounts
-                .iter()
-                .filter_map(|a| a.get(&sec_id).copied())
-                .map(|a| a as f64 * price)
-                .sum();
+            let mut bids = BTreeMap::<Ticks, DepthLevel>::new();
+            for bid in sec.bids.iter() {
+                let level = bids.entry(bid.price.0).or_insert(DepthLevel {
+                    price: sec.ticks_to_price(bid.price.0),
+                    quantity: 0,
+                    orders: 0,
+                });
+                level.quantity += bid.quantity;
+                level.orders += 1;
+            }
+            let mut asks = BTreeMap::<Ticks, DepthLevel>::new();
+            for ask in sec.asks.iter() {
+                let level = asks.entry(ask.price).or_insert(DepthLevel {
+                    price: sec.ticks_to_price(ask.price),
+                    quantity: 0,
+                    orders: 0,
+                });
+                level.quantity += ask.quantity;
+                level.orders += 1;
+            }
+            debug!(
+                "Security {} has {} bid levels and {} ask levels",
+                sec_id.0,
+                bids.len(),
+                asks.len()
+            );
+            Ok((
+                bids.into_values().collect(),
+                asks.into_values().rev().collect(),
+            ))
+        } else {
+            error!(
+                "Attempted to look up order book depth of nonexistent security {}",
+                sec_id.0
+            );
+            Err(MarketError::SecDoesNotExist(sec_id))
+        }
+    }
+
+    pub fn is_halted(&self, sec_id: SecId) -> Result<bool, MarketError> {
+        if let Some(sec) = self.securities.get(&sec_id) {
+            Ok(sec.breaker.is_halted(Instant::now()))
+        } else {
+            error!(
+                "Attempted to check trading status of nonexistent security {}",
+                sec_id.0
+            );
+            Err(MarketError::SecDoesNotExist(sec_id))
+        }
+    }
+
+    pub fn is_delisted(&self, sec_id: SecId) -> Result<bool, MarketError> {
+        if let Some(sec) = self.securities.get(&sec_id) {
+            Ok(sec.delisted.is_some())
+        } else {
+            error!(
+                "Attempted to check listing of nonexistent security {}",
+                sec_id.0
+            );
+            Err(MarketError::SecDoesNotExist(sec_id))
+        }
+    }
+
+    pub fn market_cap(&self, sec_id: SecId) -> Result<Money, MarketError> {
+        if let Some(sec) = self.securities.get(&sec_id) {
+            let Some(mcap) = sec.notional(sec.last_trade, sec.shares_outstanding) else {
+                error!("Market cap of security {} is too large", sec_id.0);
+                return Err(MarketError::ValueOverflow {
+                    sec: sec_id,
+                    price: sec.last_trade,
+                    quantity: sec.shares_outstanding,
+                });
+            };
             debug!("Market cap of security {} is {}", sec_id.0, mcap);
             Ok(mcap)
         } else {
@@ -115,12 +511,48 @@ impl Market {
         }
     }
 
-    pub fn account_value(&self, acc_id: AccId, sec_id: SecId) -> Result<f64, MarketError> {
+    /// Every account holding shares of a security, or short of them, with the most
+    /// shares first
+    pub fn shareholders(&self, sec_id: SecId) -> Result<(usize, Vec<(AccId, i64)>), MarketError> {
+        let Some(outstanding) = self.securities.get(&sec_id).map(|s| s.shares_outstanding) else {
+            error!(
+                "Attempted to list shareholders of nonexistent security {}",
+                sec_id.0
+            );
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        let mut holders: Vec<_> = self
+            .accounts
+            .iter()
+            .map(|a| (*a.key(), a.shares(sec_id)))
+            .filter(|(_, shares)| *shares != 0)
+            .collect();
+        holders.sort_by_key(|(_, shares)| Reverse(*shares));
+        debug!(
+            "Security {} has {} shareholders of {} shares outstanding",
+            sec_id.0,
+            holders.len(),
+            outstanding
+        );
+        Ok((outstanding, holders))
+    }
+
+    pub fn account_value(&self, acc_id: AccId, sec_id: SecId) -> Result<Money, MarketError> {
         if let Some(account) = self.accounts.get(&acc_id) {
             if let Some(security) = self.securities.get(&sec_id) {
-                if let Some(amount) = account.get(&sec_id) {
-                    let value = *amount as f64 * security.last_trade;
-                    if value == 0.0 {
+                if let Some(amount) = account.holdings.get(&sec_id) {
+                    let Some(value) = security.last_trade.checked_mul(*amount) else {
+                        error!(
+                            "Holdings of account {} in security {} are worth too much to value",
+                            acc_id.0, sec_id.0
+                        );
+                        return Err(MarketError::ValueOverflow {
+                            sec: sec_id,
+                            price: security.last_trade,
+                            quantity: amount.unsigned_abs() as usize,
+                        });
+                    };
+                    if value == Money::ZERO {
                         debug!(
                             "Account {} has no holdings in security {}",
                             acc_id.0, sec_id.0
@@ -138,7 +570,7 @@ impl Market {
                         "Account {} has no holdings in security {}",
                         acc_id.0, sec_id.0
                     );
-                    Ok(0.0)
+                    Ok(Money::ZERO)
                 }
             } else {
                 error!(
@@ -156,15 +588,16 @@ impl Market {
         }
     }
 
-    pub fn account_num_shares(&self, acc_id: AccId, sec_id: SecId) -> Result<usize, MarketError> {
+    /// Shares held by the account, negative if it is short
+    pub fn account_num_shares(&self, acc_id: AccId, sec_id: SecId) -> Result<i64, MarketError> {
         if let Some(account) = self.accounts.get(&acc_id) {
             if let Some(_security) = self.securities.get(&sec_id) {
-                let amount = account.get(&sec_id).unwrap_or(&0);
+                let amount = account.shares(sec_id);
                 debug!(
                     "Account {} has {} shares in security {}",
                     acc_id.0, amount, sec_id.0
                 );
-                Ok(*amount)
+                Ok(amount)
             } else {
                 error!(
                     "Attempted to look up holdings of account {} in nonexistent security {}",
@@ -185,184 +618,4115 @@ impl Market {
     //     self.thread.join().unwrap();
     // }
 
-    pub fn create_account(&self) -> AccId {
+    pub fn create_account(
+        &self,
+        margin: Option<Margin>,
+        fee_tier: usize,
+        currency: Currency,
+    ) -> Result<AccId, MarketError> {
+        if let Some(margin) = margin {
+            if !(0.0 < margin.maintenance
+                && margin.maintenance <= margin.initial
+                && margin.initial <= 1.0)
+            {
+                error!(
+                    "Attempted to create account with initial margin {} and maintenance margin {}",
+                    margin.initial, margin.maintenance
+                );
+                return Err(MarketError::InvalidMargin(margin));
+            }
+        }
+
         let id = AccId(Uuid::new_v4());
         let accs = Arc::clone(&self.accounts);
-        accs.insert(id, Default::default());
-        info!("Account {} created", id.0);
-        id
+        accs.insert(
+            id,
+            Account {
+                margin,
+                fee_tier,
+                currency: currency.clone(),
+                ..Default::default()
+            },
+        );
+        info!(
+            "Account {} created in {} with margin {:?} in fee tier {}",
+            id.0, currency, margin, fee_tier
+        );
+        Ok(id)
     }
 
-    pub fn place_bid(&self, acc: AccId, sec: SecId, price: f64) -> Result<(), MarketError> {
-        let sec_id = sec;
-        if !self.accounts.contains_key(&acc) {
-            error!("Nonexistent account {} attempted to place bid for share of security {} at max price of {}", acc.0, sec_id.0, price);
-            return Err(MarketError::AccDoesNotExist(acc));
+    /// Pays cash into an account in `currency`, its own when unset. Returns the
+    /// currency and the balance held in it.
+    pub fn deposit(
+        &self,
+        acc_id: AccId,
+        currency: Option<Currency>,
+        amount: Money,
+    ) -> Result<(Currency, Money), MarketError> {
+        if !amount.is_positive() {
+            error!("Rejected deposit of {} to account {}", amount, acc_id.0);
+            return Err(MarketError::InvalidAmount(amount));
         }
-        if let Some(mut sec) = self.securities.get_mut(&sec) {
-            sec.bids.push(Bid::new(acc, price));
+        if let Some(mut account) = self.accounts.get_mut(&acc_id) {
+            let currency = currency.unwrap_or_else(|| account.currency.clone());
+            let entry = account.post(EntryKind::Deposit, &currency, amount, SystemTime::now());
             info!(
-                "Account {} placed bid for share of {} at max price of {}",
-                acc.0, sec_id.0, price
+                "Account {} deposited {} {}, leaving a balance of {}",
+                acc_id.0, amount, currency, entry.balance
             );
-            Ok(())
+            let balance = entry.balance;
+            let _ = self.statements.send((acc_id, entry));
+            Ok((currency, balance))
         } else {
-            error!("Account {} attempted to place bid for share of nonexistent security {} at max price of {}", acc.0, sec_id.0, price);
-            Err(MarketError::SecDoesNotExist(sec))
+            error!(
+                "Attempted to deposit {} to nonexistent account {}",
+                amount, acc_id.0
+            );
+            Err(MarketError::AccDoesNotExist(acc_id))
         }
     }
 
-    pub fn place_ask(&self, acc: AccId, sec: SecId, price: f64) -> Result<(), MarketError> {
-        let sec_id = sec;
-        if !self.accounts.contains_key(&acc) {
-            error!("Nonexistent account {} attempted to place ask for share of security {} at min price of {}", acc.0, sec_id.0, price);
-            return Err(MarketError::AccDoesNotExist(acc));
-        }
-        if let Some(mut sec) = self.securities.get_mut(&sec) {
-            sec.asks.push(Ask::new(acc, price));
-            info!(
-                "Account {} placed ask for share of {} at min price of {}",
-                acc.0, sec_id.0, price
+    /// Checks an order against the listing, tick size, lot size and price band of
+    /// its security, returning its value in the currency of the security
+    fn validate_order(
+        &self,
+        sec_id: SecId,
+        price: Money,
+        quantity: usize,
+        display: Option<usize>,
+    ) -> Result<(Money, Currency), MarketError> {
+        let Some(sec) = self.securities.get(&sec_id) else {
+            error!(
+                "Attempted to place order for {} shares of nonexistent security {} at {}",
+                quantity, sec_id.0, price
             );
-            Ok(())
-        } else {
-            error!("Account {} attempted to place ask for share of nonexistent security {} at min price of {}", acc.0, sec_id.0, price);
-            Err(MarketError::SecDoesNotExist(sec))
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        sec.price_to_ticks(sec_id, price)?;
+        sec.check_quantity(sec_id, quantity)?;
+        if let Some(display) = display {
+            sec.check_quantity(sec_id, display)?;
         }
+        sec.check_order_price(sec_id, price)?;
+        let value = sec.check_value(sec_id, price, quantity)?;
+        Ok((value, sec.currency.clone()))
     }
 
-    pub fn list_securities(&self) -> Vec<SecId> {
-        let map = Arc::as_ref(&self.securities);
-        map.iter().map(|s| s.pair().0.clone()).collect::<Vec<_>>()
+    /// Checks a stop against the listing, tick size, lot size, last trade and price
+    /// band of its security, returning its value at its limit, or its trigger
+    /// without one, in the currency of the security
+    fn validate_stop(
+        &self,
+        sec_id: SecId,
+        side: Side,
+        trigger: Money,
+        limit: Option<Money>,
+        quantity: usize,
+    ) -> Result<(Money, Currency), MarketError> {
+        let Some(sec) = self.securities.get(&sec_id) else {
+            error!(
+                "Attempted to place {:?} stop for {} shares of nonexistent security {} triggered at {}",
+                side, quantity, sec_id.0, trigger
+            );
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        sec.check_listed(sec_id)?;
+        let trigger_ticks = sec.price_to_ticks(sec_id, trigger)?;
+        if let Some(limit) = limit {
+            sec.price_to_ticks(sec_id, limit)?;
+        }
+        sec.check_quantity(sec_id, quantity)?;
+        let value = sec.check_value(sec_id, limit.unwrap_or(trigger), quantity)?;
+        sec.check_stop(sec_id, side, trigger_ticks, limit)?;
+        Ok((value, sec.currency.clone()))
     }
 
-    pub fn create_security(&self, founding_shares: usize, founding_price: f64) -> (SecId, AccId) {
-        let sec_id = SecId(Uuid::new_v4());
-        self.securities.insert(sec_id, Security::default());
-        let acc_id = self.create_account();
-        let mut account = self.accounts.get_mut(&acc_id).unwrap();
-        account.insert(sec_id, founding_shares);
-        let mut security = self.securities.get_mut(&sec_id).unwrap();
-        security.asks.reserve(founding_shares);
-        for _ in 0..founding_shares {
-            security.asks.push(Ask {
-                price: NotNan::new(founding_price).unwrap(),
-                account: acc_id,
-            });
-            security.last_trade = founding_price;
+    /// Runs every risk check against an order once it has been validated, before
+    /// any security is locked
+    fn check_risk(&self, order: OrderRequest) -> Result<(), MarketError> {
+        if self.risk_checks.is_empty() {
+            return Ok(());
         }
-        info!("Security {} created", sec_id.0);
-
-        (sec_id, acc_id)
+        // Limits are in the base currency, so they mean the same for every security
+        let notional = self.convert(order.value, &order.currency, &Currency::default())?;
+        let open_orders = self
+            .securities
+            .iter()
+            .map(|sec| {
+                sec.bids
+                    .iter()
+                    .filter(|b| b.account == order.account)
+                    .count()
+                    + sec
+                        .asks
+                        .iter()
+                        .filter(|a| a.account == order.account)
+                        .count()
+                    + sec
+                        .stops
+                        .iter()
+                        .filter(|s| s.account == order.account)
+                        .count()
+            })
+            .sum();
+        let position = self
+            .accounts
+            .get(&order.account)
+            .map_or(0, |a| a.shares(order.sec));
+        let context = RiskContext {
+            open_orders,
+            position,
+            notional,
+        };
+        for check in self.risk_checks.iter() {
+            if let Err(err) = check.check(&order, &context) {
+                error!(
+                    "Rejected {:?} order of account {} for {} shares of security {} at {}: {}",
+                    order.side, order.account.0, order.quantity, order.sec.0, order.price, err
+                );
+                return Err(err);
+            }
+        }
+        Ok(())
     }
 
-    pub fn run_market_loop(market: Market) {
-        let securities = market.securities;
-        let accounts = market.accounts;
-        for mut sec in securities.iter_mut() {
-            let (sec_id, sec) = sec.pair_mut();
-            trace!("Processing security {}", sec_id.0);
-            'find: while let (Some(bid), Some(ask)) = (sec.bids.peek(), sec.asks.peek()) {
-                trace!(
-                    "Cheching a bid by account {} of {} against an ask by account {} of {}",
-                    bid.account.0,
-                    bid.price.0,
-                    ask.account.0,
-                    ask.price
-                );
+    /// The value of every security by its last trade in `currency`, leaving out
+    /// futures as their value is settled into cash, and securities without a rate
+    fn mark_to_market(&self, currency: &Currency, rates: &FxRates) -> HashMap<SecId, Money> {
+        self.securities
+            .iter()
+            .filter(|sec| sec.delisted.is_none() && sec.future.is_none())
+            .filter_map(|sec| {
+                let price = rates.convert(sec.last_trade, &sec.currency, currency)?;
+                Some((*sec.key(), price))
+            })
+            .collect()
+    }
 
-                if bid.price.0 >= ask.price {
-                    trace!("Processing possible transaction");
-                    let bid = sec.bids.pop().unwrap();
-                    let ask = sec.asks.pop().unwrap();
+    /// Checks that an account trading on margin can cover the positions an order
+    /// would open with its equity
+    fn check_margin(
+        &self,
+        acc_id: AccId,
+        sec_id: SecId,
+        side: Side,
+        price: Money,
+        quantity: usize,
+    ) -> Result<(), MarketError> {
+        let Some((margin, currency)) = self
+            .accounts
+            .get(&acc_id)
+            .and_then(|a| Some((a.margin?, a.currency.clone())))
+        else {
+            return Ok(());
+        };
+        let Some(sec_currency) = self.securities.get(&sec_id).map(|s| s.currency.clone()) else {
+            return Ok(());
+        };
+        let rates = self.rates();
+        let prices = self.mark_to_market(&currency, &rates);
+        let price = self.convert(price, &sec_currency, &currency)?;
+        let account = self.accounts.get(&acc_id).unwrap();
+        let shares = account.shares(sec_id);
+        // Only the part of the order that is not closing an existing position adds to it
+        let closing = match side {
+            Side::Buy => (-shares).max(0),
+            Side::Sell => shares.max(0),
+        };
+        let opening = i64::try_from(quantity)
+            .ok()
+            .and_then(|quantity| quantity.checked_sub(closing))
+            .map(|opening| opening.max(0));
+        let Some(required) = opening
+            .and_then(|opening| price.checked_mul(opening))
+            .and_then(|value| account.exposure(&prices).checked_add(value))
+            .map(|exposure| exposure.scale_by(margin.initial))
+        else {
+            error!(
+                "Margin of account {} for {:?} order of {} shares of security {} at {} is too large",
+                acc_id.0, side, quantity, sec_id.0, price
+            );
+            return Err(MarketError::ValueOverflow {
+                sec: sec_id,
+                price,
+                quantity,
+            });
+        };
+        let equity = account.equity(&prices, &rates);
+        if equity < required {
+            error!(
+                "Account {} with equity {} can not cover margin of {} for {:?} order of {} shares of security {} at {}",
+                acc_id.0, equity, required, side, quantity, sec_id.0, price
+            );
+            return Err(MarketError::InsufficientMargin {
+                acc: acc_id,
+                equity,
+                required,
+            });
+        }
+        Ok(())
+    }
 
-                    let price = bid.price;
-                    trace!("Agreed price: {}", price.0);
+    /// Rejects bids of accounts not trading on margin that the cash they hold in the
+    /// currency of the security does not cover, along with the most they could be
+    /// charged in fees, their other bids in it and the strike of the puts they wrote.
+    /// Futures are covered by their own margin instead.
+    fn check_cash(
+        &self,
+        acc_id: AccId,
+        sec_id: SecId,
+        price: Money,
+        quantity: usize,
+    ) -> Result<(), MarketError> {
+        let Some(currency) = self
+            .securities
+            .get(&sec_id)
+            .filter(|sec| sec.future.is_none())
+            .map(|sec| sec.currency.clone())
+        else {
+            return Ok(());
+        };
+        let Some((fee_tier, cash, holdings)) = self
+            .accounts
+            .get(&acc_id)
+            .filter(|account| account.margin.is_none())
+            .map(|account| {
+                (
+                    account.fee_tier,
+                    account.cash(&currency),
+                    account.holdings.clone(),
+                )
+            })
+        else {
+            return Ok(());
+        };
+        let cost = self
+            .securities
+            .get(&sec_id)
+            .and_then(|sec| sec.bid_cost(price, quantity, fee_tier));
+        let collateral = self
+            .option_cover(&holdings)
+            .map(|(_, cash)| cash.get(&currency).copied().unwrap_or_default());
+        let required = self
+            .securities
+            .iter()
+            .filter(|sec| sec.currency == currency && sec.future.is_none())
+            .map(|sec| Some(sec.committed_cash(acc_id, fee_tier)))
+            .chain([cost, collateral])
+            .try_fold(Money::ZERO, |total, cost| total.checked_add(cost?))
+            .unwrap_or(Money::MAX);
+        if cash < required {
+            error!(
+                "Account {} with {} {} can not cover bids worth {} in security {} and others",
+                acc_id.0, cash, currency, required, sec_id.0
+            );
+            return Err(MarketError::InsufficientCash {
+                acc: acc_id,
+                cash,
+                required,
+            });
+        }
+        Ok(())
+    }
 
-                    let mut seller = accounts.get_mut(&ask.account).unwrap();
-                    let (seller_id, seller) = seller.pair_mut();
+    /// Shares of each underlying and cash in each currency covering the options among
+    /// `holdings`, None if more than can be held
+    fn option_cover(
+        &self,
+        holdings: &HashMap<SecId, i64>,
+    ) -> Option<(HashMap<SecId, i64>, HashMap<Currency, Money>)> {
+        let mut shares: HashMap<SecId, i64> = HashMap::new();
+        let mut cash: HashMap<Currency, Money> = HashMap::new();
+        for (sec_id, contracts) in holdings.iter().filter(|(_, contracts)| **contracts != 0) {
+            let Some((option, currency)) = self.securities.get(sec_id).and_then(|sec| {
+                let option = sec.option.filter(|_| sec.delisted.is_none())?;
+                Some((option, sec.currency.clone()))
+            }) else {
+                continue;
+            };
+            let (needed, collateral) = option.cover(*contracts)?;
+            if needed != 0 {
+                let total = shares.entry(option.underlying).or_default();
+                *total = total.checked_add(needed)?;
+            }
+            if collateral != Money::ZERO {
+                let total = cash.entry(currency).or_default();
+                *total = total.checked_add(collateral)?;
+            }
+        }
+        Some((shares, cash))
+    }
 
-                    let mut buyer = accounts.get_mut(&bid.account).unwrap();
-                    let (buyer_id, buyer) = buyer.pair_mut();
+    /// Checks that an account still covers the options it would write or hold if an
+    /// order for `quantity` filled: written calls and held puts with shares of the
+    /// underlying, written puts with cash for the strike. Exercising them then never
+    /// opens a short position past the short selling limits of the underlying.
+    fn check_option_cover(
+        &self,
+        acc_id: AccId,
+        sec_id: SecId,
+        side: Side,
+        quantity: usize,
+    ) -> Result<(), MarketError> {
+        let Some((option, derivative)) = self
+            .securities
+            .get(&sec_id)
+            .map(|sec| (sec.option, sec.is_derivative()))
+        else {
+            return Ok(());
+        };
+        let Some(mut holdings) = self
+            .accounts
+            .get(&acc_id)
+            .map(|account| account.holdings.clone())
+        else {
+            return Ok(());
+        };
+        let overflow = || {
+            error!(
+                "Options of account {} covered after {:?} order of {} shares of security {} are more than can be held",
+                acc_id.0, side, quantity, sec_id.0
+            );
+            MarketError::QuantityOverflow {
+                sec: sec_id,
+                quantity,
+            }
+        };
+        let held = holdings.get(&sec_id).copied().unwrap_or(0);
+        let change = i64::try_from(quantity).map_err(|_| overflow())?;
+        let after = match side {
+            Side::Buy => held.checked_add(change),
+            Side::Sell => held.checked_sub(change),
+        }
+        .ok_or_else(overflow)?;
+        // Only writing options, buying puts or selling shares can leave options uncovered
+        let uncovering = match (option, side) {
+            (Some(_), Side::Sell) => after < 0,
+            (Some(option), Side::Buy) => option.kind == OptionKind::Put && after > 0,
+            (None, Side::Sell) => !derivative,
+            (None, Side::Buy) => false,
+        };
+        if !uncovering {
+            return Ok(());
+        }
+        holdings.insert(sec_id, after);
+        let (shares, cash) = self.option_cover(&holdings).ok_or_else(overflow)?;
 
-                    if !seller.contains_key(sec_id) {
-                        warn!("Seller account {} has no holdings in security {}; Transaction unavailable", seller_id.0, sec_id.0);
-                        continue;
-                    }
+        for (underlying, needed) in shares {
+            let held = holdings.get(&underlying).copied().unwrap_or(0);
+            if held < needed {
+                error!(
+                    "Account {} holding {} shares of security {} can not cover options for {} after {:?} order of {} shares of security {}",
+                    acc_id.0, held, underlying.0, needed, side, quantity, sec_id.0
+                );
+                return Err(MarketError::InsufficientShares {
+                    acc: acc_id,
+                    sec: underlying,
+                    held,
+                    quantity: needed as usize,
+                });
+            }
+        }
+        for (currency, required) in cash {
+            let Some(held) = self
+                .accounts
+                .get(&acc_id)
+                .map(|account| account.cash(&currency))
+            else {
+                continue;
+            };
+            if held < required {
+                error!(
+                    "Account {} with {} {} can not cover the strike of {} on puts written after {:?} order of {} contracts of security {}",
+                    acc_id.0, held, currency, required, side, quantity, sec_id.0
+                );
+                return Err(MarketError::InsufficientCash {
+                    acc: acc_id,
+                    cash: held,
+                    required,
+                });
+            }
+        }
+        Ok(())
+    }
 
-                    if *seller.get(sec_id).unwrap() < 1 {
-                        warn!("Seller account {} has no holdings in security {}; Transaction unavailable", seller_id.0, sec_id.0);
-                        continue;
-                    }
+    /// Issues margin calls to accounts whose equity fell below their maintenance
+    /// margin, or whose cash fell below the maintenance margin of their futures, and
+    /// places orders at the edge of the price band to close each of the positions
+    /// margined. Returns the number of margin calls.
+    pub fn liquidate_undermargined(&self) -> usize {
+        let rates = self.rates();
+        let currencies: HashSet<_> = self
+            .accounts
+            .iter()
+            .map(|account| account.currency.clone())
+            .collect();
+        let prices: HashMap<_, _> = currencies
+            .into_iter()
+            .map(|currency| {
+                let prices = self.mark_to_market(&currency, &rates);
+                (currency, prices)
+            })
+            .collect();
+        let maintenance: HashMap<_, _> = self
+            .securities
+            .iter()
+            .filter(|sec| sec.delisted.is_none())
+            .filter_map(|sec| {
+                let margin = sec.future.as_ref()?.maintenance_margin;
+                Some((*sec.key(), (margin, sec.currency.clone())))
+            })
+            .collect();
+        let calls: Vec<_> = self
+            .accounts
+            .iter()
+            .filter_map(|account| {
+                let prices = prices.get(&account.currency)?;
+                let margin_call = account.margin.and_then(|margin| {
+                    let exposure = account.exposure(prices);
+                    let requirement = exposure.scale_by(margin.maintenance);
+                    let equity = account.equity(prices, &rates);
+                    (exposure.is_positive() && equity < requirement).then_some((
+                        equity,
+                        requirement,
+                        false,
+                    ))
+                });
+                let futures_call = || {
+                    let requirement: Money = account
+                        .holdings
+                        .iter()
+                        .filter_map(|(sec_id, contracts)| {
+                            let (margin, currency) = maintenance.get(sec_id)?;
+                            let margin = margin.saturating_mul(contracts.saturating_abs());
+                            rates.convert(margin, currency, &account.currency)
+                        })
+                        .fold(Money::ZERO, Money::saturating_add);
+                    let cash = account.cash_value(&rates);
+                    (requirement.is_positive() && cash < requirement).then_some((
+                        cash,
+                        requirement,
+                        true,
+                    ))
+                };
+                let (equity, requirement, futures_only) = margin_call.or_else(futures_call)?;
+                let positions: Vec<_> = account
+                    .holdings
+                    .iter()
+                    .filter(|(sec_id, shares)| {
+                        **shares != 0 && (!futures_only || maintenance.contains_key(sec_id))
+                    })
+                    .map(|(sec_id, shares)| {
+                        (*sec_id, *shares, account.liquidations.get(sec_id).copied())
+                    })
+                    .collect();
+                Some((
+                    MarginCall {
+                        account: *account.key(),
+                        equity,
+                        requirement,
+                    },
+                    positions,
+                ))
+            })
+            .collect();
 
-                    trace!("Seller account {} has at least one share of security {}, transaction will go ahead", seller_id.0, sec_id.0);
+        for (call, positions) in calls.iter() {
+            warn!(
+                "Margin call for account {} with equity {} under its maintenance margin of {}",
+                call.account.0, call.equity, call.requirement
+            );
+            let _ = self.margin_calls.send(call.clone());
 
-                    *seller.get_mut(sec_id).unwrap() -= 1;
-                    trace!(
-                        "Removed one share of security {} from seller account {}",
-                        sec_id.0,
-                        seller_id.0
-                    );
-                    buyer
-                        .entry(*sec_id)
-                        .and_modify(|a| *a += 1)
-                        .or_insert_with(|| 1);
+            for (sec_id, shares, pending) in positions.iter().copied() {
+                let Some(mut sec) = self.securities.get_mut(&sec_id) else {
+                    continue;
+                };
+                // Nothing trades on the books of delisted or expired securities
+                if sec.delisted.is_some() {
+                    continue;
+                }
+                let open = pending.is_some_and(|id| {
+                    sec.bids.iter().any(|b| b.id == id) || sec.asks.iter().any(|a| a.id == id)
+                });
+                if open {
                     trace!(
-                        "Added one share of security {} to buyer account {}",
-                        sec_id.0,
-                        buyer_id.0
-                    );
-
-                    info!(
-                        "Transaction occured between buyer {} and seller {}:",
-                        buyer_id.0, seller_id.0
+                        "Liquidation of account {} in security {} is still open",
+                        call.account.0,
+                        sec_id.0
                     );
-                    sec.last_trade = *price.0;
+                    continue;
+                }
 
-                    info!("Security {} sold for {}", sec_id.0, price.0);
+                let (low, high) = sec.band_ticks();
+                let quantity = shares.unsigned_abs() as usize;
+                let order = if shares > 0 {
+                    let ask = Ask::new(call.account, low, quantity, self.next_seq());
+                    let price = sec.ticks_to_price(low);
+                    let _ = self.executions.send(Execution::of_ask(
+                        sec_id,
+                        &ask,
+                        ExecKind::Liquidation,
+                        price,
+                        quantity,
+                    ));
+                    let id = ask.id;
+                    sec.asks.push(ask);
+                    id
                 } else {
-                    debug!("No available transactions");
-                    break 'find;
+                    let bid = Bid::new(call.account, high, quantity, self.next_seq());
+                    let price = sec.ticks_to_price(high);
+                    let _ = self.executions.send(Execution::of_bid(
+                        sec_id,
+                        &bid,
+                        ExecKind::Liquidation,
+                        price,
+                        quantity,
+                    ));
+                    let id = bid.id;
+                    sec.bids.push(bid);
+                    id
+                };
+                drop(sec);
+                info!(
+                    "Placed order {} to liquidate {} shares of security {} held by account {}",
+                    order.0, shares, sec_id.0, call.account.0
+                );
+                if let Some(mut account) = self.accounts.get_mut(&call.account) {
+                    account.liquidations.insert(sec_id, order);
                 }
             }
         }
+        calls.len()
     }
-}
-
-#[derive(Error, Debug)]
-pub enum MarketError {
-    #[error("Security {} does not exist", 0.0)]
-    SecDoesNotExist(SecId),
-    #[error("Account {} does not exist", 0.0)]
-    AccDoesNotExist(AccId),
-    #[error("No bids are placed for security {}", 0.0)]
-    NoBids(SecId),
-    #[error("No asks are placed for security {}", 0.0)]
-    NoAsks(SecId),
-}
 
-impl From<MarketError> for Status {
-    fn from(value: MarketError) -> Self {
-        match value {
-            MarketError::SecDoesNotExist(sec) => {
-                Status::failed_precondition(format!("Security {} does not exist", sec.0))
-            }
-            MarketError::AccDoesNotExist(acc) => {
-                Status::failed_precondition(format!("Account {} does not exist", acc.0))
-            }
-            MarketError::NoBids(sec) => {
-                Status::ok(format!("No bids are placed for security {}", sec.0))
-            }
-            MarketError::NoAsks(sec) => {
-                Status::ok(format!("No asks are placed for security {}", sec.0))
-            }
+    pub fn place_bid(
+        &self,
+        acc: AccId,
+        sec: SecId,
+        price: Money,
+        quantity: usize,
+        display: Option<usize>,
+        time_in_force: TimeInForce,
+    ) -> Result<OrderId, MarketError> {
+        let sec_id = sec;
+        if !self.accounts.contains_key(&acc) {
+            error!("Nonexistent account {} attempted to place bid for {} shares of security {} at max price of {}", acc.0, quantity, sec_id.0, price);
+            return Err(MarketError::AccDoesNotExist(acc));
         }
-    }
-}
-
+        self.check_margin(acc, sec_id, Side::Buy, price, quantity)?;
+        self.check_cash(acc, sec_id, price, quantity)?;
+        self.check_futures_margin(acc, sec_id, Side::Buy, quantity)?;
+        self.check_option_cover(acc, sec_id, Side::Buy, quantity)?;
+        let expires_at = self.expiry(sec_id, time_in_force)?;
+        let (value, currency) = self.validate_order(sec_id, price, quantity, display)?;
+        self.check_risk(OrderRequest {
+            account: acc,
+            sec: sec_id,
+            side: Side::Buy,
+            price,
+            quantity,
+            value,
+            currency,
+        })?;
+        if let Some(mut sec) = self.securities.get_mut(&sec) {
+            // The band moves with every trade, so it is checked again as the order is placed
+            let ticks = sec.price_to_ticks(sec_id, price)?;
+            sec.check_order_price(sec_id, price)?;
+            let bid = Bid::new(acc, ticks, quantity, self.next_seq())
+                .with_display(display)
+                .with_expiry(expires_at);
+            let id = bid.id;
+            sec.bids.push(bid);
+            info!(
+                "Account {} placed bid {} for {} shares of {} at max price of {}",
+                acc.0, id.0, quantity, sec_id.0, price
+            );
+            Ok(id)
+        } else {
+            error!("Account {} attempted to place bid for {} shares of nonexistent security {} at max price of {}", acc.0, quantity, sec_id.0, price);
+            Err(MarketError::SecDoesNotExist(sec))
+        }
+    }
+
+    pub fn place_ask(
+        &self,
+        acc: AccId,
+        sec: SecId,
+        price: Money,
+        quantity: usize,
+        display: Option<usize>,
+        time_in_force: TimeInForce,
+    ) -> Result<OrderId, MarketError> {
+        let sec_id = sec;
+        if !self.accounts.contains_key(&acc) {
+            error!("Nonexistent account {} attempted to place ask for {} shares of security {} at min price of {}", acc.0, quantity, sec_id.0, price);
+            return Err(MarketError::AccDoesNotExist(acc));
+        }
+        self.check_margin(acc, sec_id, Side::Sell, price, quantity)?;
+        self.check_futures_margin(acc, sec_id, Side::Sell, quantity)?;
+        self.check_option_cover(acc, sec_id, Side::Sell, quantity)?;
+        let expires_at = self.expiry(sec_id, time_in_force)?;
+        let (value, currency) = self.validate_order(sec_id, price, quantity, display)?;
+        self.check_risk(OrderRequest {
+            account: acc,
+            sec: sec_id,
+            side: Side::Sell,
+            price,
+            quantity,
+            value,
+            currency,
+        })?;
+        if let Some(mut sec) = self.securities.get_mut(&sec) {
+            // The band moves with every trade, so it is checked again as the order is placed
+            let ticks = sec.price_to_ticks(sec_id, price)?;
+            sec.check_order_price(sec_id, price)?;
+            let ask = Ask::new(acc, ticks, quantity, self.next_seq())
+                .with_display(display)
+                .with_expiry(expires_at);
+            let id = ask.id;
+            sec.asks.push(ask);
+            info!(
+                "Account {} placed ask {} for {} shares of {} at min price of {}",
+                acc.0, id.0, quantity, sec_id.0, price
+            );
+            Ok(id)
+        } else {
+            error!("Account {} attempted to place ask for {} shares of nonexistent security {} at min price of {}", acc.0, quantity, sec_id.0, price);
+            Err(MarketError::SecDoesNotExist(sec))
+        }
+    }
+
+    /// Places an order that enters the book once the last trade reaches `trigger`,
+    /// as a limit order at `limit` or, without one, as a market order
+    pub fn place_stop(
+        &self,
+        acc: AccId,
+        sec: SecId,
+        side: Side,
+        trigger: Money,
+        limit: Option<Money>,
+        quantity: usize,
+    ) -> Result<OrderId, MarketError> {
+        let sec_id = sec;
+        if !self.accounts.contains_key(&acc) {
+            error!("Nonexistent account {} attempted to place {:?} stop for {} shares of security {} triggered at {}", acc.0, side, quantity, sec_id.0, trigger);
+            return Err(MarketError::AccDoesNotExist(acc));
+        }
+        if side == Side::Buy {
+            self.check_cash(acc, sec_id, limit.unwrap_or(trigger), quantity)?;
+        }
+        self.check_option_cover(acc, sec_id, side, quantity)?;
+        let (value, currency) = self.validate_stop(sec_id, side, trigger, limit, quantity)?;
+        self.check_risk(OrderRequest {
+            account: acc,
+            sec: sec_id,
+            side,
+            price: limit.unwrap_or(trigger),
+            quantity,
+            value,
+            currency,
+        })?;
+        if let Some(mut sec) = self.securities.get_mut(&sec) {
+            // The last trade may have reached the trigger since the stop was checked
+            let trigger_ticks = sec.price_to_ticks(sec_id, trigger)?;
+            let limit_ticks = limit
+                .map(|limit| sec.price_to_ticks(sec_id, limit))
+                .transpose()?;
+            sec.check_stop(sec_id, side, trigger_ticks, limit)?;
+            let stop = StopOrder::new(
+                acc,
+                side,
+                trigger_ticks,
+                limit_ticks,
+                quantity,
+                self.next_seq(),
+            );
+            let id = stop.id;
+            sec.stops.push(stop);
+            info!(
+                "Account {} placed {:?} stop {} for {} shares of {} triggered at {} with limit {:?}",
+                acc.0, side, id.0, quantity, sec_id.0, trigger, limit
+            );
+            Ok(id)
+        } else {
+            error!("Account {} attempted to place {:?} stop for {} shares of nonexistent security {} triggered at {}", acc.0, side, quantity, sec_id.0, trigger);
+            Err(MarketError::SecDoesNotExist(sec))
+        }
+    }
+
+    /// Cancels a stop that has not triggered yet, returning the shares it was for
+    pub fn cancel_stop(&self, acc: AccId, order: OrderId) -> Result<usize, MarketError> {
+        for mut sec in self.securities.iter_mut() {
+            let (sec_id, sec) = sec.pair_mut();
+            let Some(index) = sec.stops.iter().position(|s| s.id == order) else {
+                continue;
+            };
+            if sec.stops[index].account != acc {
+                error!(
+                    "Account {} attempted to cancel stop {} owned by account {}",
+                    acc.0, order.0, sec.stops[index].account.0
+                );
+                return Err(MarketError::NotOrderOwner { order, acc });
+            }
+            let mut stop = sec.stops.remove(index);
+            let quantity = stop.quantity;
+            stop.quantity = 0;
+            info!(
+                "Account {} canceled stop {} for {} shares of security {}",
+                acc.0, order.0, quantity, sec_id.0
+            );
+            let _ = self.executions.send(Execution::of_stop(
+                *sec_id,
+                &stop,
+                ExecKind::Canceled,
+                sec.ticks_to_price(stop.trigger),
+                quantity,
+            ));
+            return Ok(quantity);
+        }
+        error!(
+            "Account {} attempted to cancel nonexistent stop {}",
+            acc.0, order.0
+        );
+        Err(MarketError::OrderDoesNotExist(order))
+    }
+
+    /// Removes every order that has passed its expiry time, returning how many were
+    /// removed
+    pub fn expire_orders(&self) -> usize {
+        let now = SystemTime::now();
+        let mut expired = 0;
+        for mut sec in self.securities.iter_mut() {
+            let (sec_id, sec) = sec.pair_mut();
+            // Most sweeps find nothing, so leave the books alone unless they must change
+            if !sec.bids.iter().any(|b| b.is_expired(now))
+                && !sec.asks.iter().any(|a| a.is_expired(now))
+            {
+                continue;
+            }
+            let (bids, asks) = (std::mem::take(&mut sec.bids), std::mem::take(&mut sec.asks));
+            for bid in bids {
+                if bid.is_expired(now) {
+                    sec.expire_bid(*sec_id, bid, &self.executions);
+                    expired += 1;
+                } else {
+                    sec.bids.push(bid);
+                }
+            }
+            for ask in asks {
+                if ask.is_expired(now) {
+                    sec.expire_ask(*sec_id, ask, &self.executions);
+                    expired += 1;
+                } else {
+                    sec.asks.push(ask);
+                }
+            }
+        }
+        expired
+    }
+
+    /// Charges borrow fees on short positions and buys in those worth more than the
+    /// short selling limit of their security
+    pub fn settle_borrows(&self) {
+        let now = Instant::now();
+        for mut sec in self.securities.iter_mut() {
+            let (sec_id, sec) = sec.pair_mut();
+            let Some(short_selling) = sec.short_selling else {
+                continue;
+            };
+            if sec.delisted.is_some() || sec.borrowers.is_empty() {
+                continue;
+            }
+            for acc_id in sec.borrowers.clone() {
+                let Some(mut account) = self.accounts.get_mut(&acc_id) else {
+                    sec.borrowers.remove(&acc_id);
+                    continue;
+                };
+                let account = account.value_mut();
+                let shares = account.shares(*sec_id);
+                if shares >= 0 {
+                    sec.borrowers.remove(&acc_id);
+                    continue;
+                }
+
+                let fee = sec.accrue_borrow_fee(*sec_id, account, now);
+                if fee.is_positive() {
+                    debug!(
+                        "Charged account {} {} for borrowing {} shares of security {}",
+                        acc_id.0, fee, -shares, sec_id.0
+                    );
+                }
+
+                let value = sec.last_trade * -shares;
+                let borrow = account.borrows.get_mut(sec_id).unwrap();
+                let unpaid = borrow.owed.is_positive();
+                if value <= short_selling.max_value && !unpaid {
+                    continue;
+                }
+                if let Some(buy_in) = borrow.buy_in {
+                    if sec.bids.iter().any(|b| b.id == buy_in) {
+                        trace!(
+                            "Buy-in {} of account {} for security {} is still open",
+                            buy_in.0,
+                            acc_id.0,
+                            sec_id.0
+                        );
+                        continue;
+                    }
+                }
+
+                // An account that can not pay its borrow fees is bought in entirely
+                let excess = if unpaid {
+                    -shares as usize
+                } else {
+                    (-shares - sec.short_capacity(sec.last_trade)) as usize
+                };
+                let quantity = excess.div_ceil(sec.lot_size) * sec.lot_size;
+                let (_, high) = sec.band_ticks();
+                let bid = Bid::new(acc_id, high, quantity, self.next_seq());
+                let price = sec.ticks_to_price(high);
+                if unpaid {
+                    warn!(
+                        "Account {} owes {} in borrow fees on security {}; buying in {} shares at max price of {}",
+                        acc_id.0, borrow.owed, sec_id.0, quantity, price
+                    );
+                } else {
+                    warn!(
+                        "Short position of account {} in security {} is worth {}, over the limit of {}; buying in {} shares at max price of {}",
+                        acc_id.0, sec_id.0, value, short_selling.max_value, quantity, price
+                    );
+                }
+                let _ = self.executions.send(Execution::of_bid(
+                    *sec_id,
+                    &bid,
+                    ExecKind::BuyIn,
+                    price,
+                    quantity,
+                ));
+                borrow.buy_in = Some(bid.id);
+                sec.bids.push(bid);
+            }
+        }
+    }
+
+    /// Changes the price and quantity of a resting order. Time priority is kept only
+    /// if the price is unchanged and the quantity does not grow. Returns whether
+    /// priority was kept.
+    pub fn replace_order(
+        &self,
+        acc: AccId,
+        order: OrderId,
+        price: Money,
+        quantity: usize,
+    ) -> Result<bool, MarketError> {
+        for mut sec in self.securities.iter_mut() {
+            let (sec_id, sec) = sec.pair_mut();
+            if let Some(bid) = sec.bids.iter().find(|b| b.id == order) {
+                if bid.account != acc {
+                    error!(
+                        "Account {} attempted to replace bid {} owned by account {}",
+                        acc.0, order.0, bid.account.0
+                    );
+                    return Err(MarketError::NotOrderOwner { order, acc });
+                }
+                let ticks = sec.price_to_ticks(*sec_id, price)?;
+                sec.check_quantity(*sec_id, quantity)?;
+                sec.check_value(*sec_id, price, quantity)?;
+                sec.check_order_price(*sec_id, price)?;
+
+                let mut bids = std::mem::take(&mut sec.bids).into_vec();
+                let bid = bids.iter_mut().find(|b| b.id == order).unwrap();
+                let keep_priority = bid.price.0 == ticks && quantity <= bid.remaining();
+                bid.price = Reverse(ticks);
+                bid.set_remaining(quantity);
+                if !keep_priority {
+                    bid.seq = self.next_seq();
+                }
+                sec.bids = bids.into();
+                info!(
+                    "Account {} replaced bid {} for security {} with {} shares at max price of {}",
+                    acc.0, order.0, sec_id.0, quantity, price
+                );
+                return Ok(keep_priority);
+            }
+            if let Some(ask) = sec.asks.iter().find(|a| a.id == order) {
+                if ask.account != acc {
+                    error!(
+                        "Account {} attempted to replace ask {} owned by account {}",
+                        acc.0, order.0, ask.account.0
+                    );
+                    return Err(MarketError::NotOrderOwner { order, acc });
+                }
+                let ticks = sec.price_to_ticks(*sec_id, price)?;
+                sec.check_quantity(*sec_id, quantity)?;
+                sec.check_value(*sec_id, price, quantity)?;
+                sec.check_order_price(*sec_id, price)?;
+
+                let mut asks = std::mem::take(&mut sec.asks).into_vec();
+                let ask = asks.iter_mut().find(|a| a.id == order).unwrap();
+                let keep_priority = ask.price == ticks && quantity <= ask.remaining();
+                ask.price = ticks;
+                ask.set_remaining(quantity);
+                if !keep_priority {
+                    ask.seq = self.next_seq();
+                }
+                sec.asks = asks.into();
+                info!(
+                    "Account {} replaced ask {} for security {} with {} shares at min price of {}",
+                    acc.0, order.0, sec_id.0, quantity, price
+                );
+                return Ok(keep_priority);
+            }
+            if let Some(stop) = sec.stops.iter().find(|s| s.id == order) {
+                if stop.account != acc {
+                    error!(
+                        "Account {} attempted to replace stop {} owned by account {}",
+                        acc.0, order.0, stop.account.0
+                    );
+                    return Err(MarketError::NotOrderOwner { order, acc });
+                }
+                let (side, limit) = (stop.side, stop.limit);
+                let limit = limit.map(|limit| sec.ticks_to_price(limit));
+                let ticks = sec.price_to_ticks(*sec_id, price)?;
+                sec.check_quantity(*sec_id, quantity)?;
+                sec.check_value(*sec_id, limit.unwrap_or(price), quantity)?;
+                sec.check_stop(*sec_id, side, ticks, limit)?;
+
+                let stop = sec.stops.iter_mut().find(|s| s.id == order).unwrap();
+                let keep_priority = stop.trigger == ticks && quantity <= stop.quantity;
+                stop.trigger = ticks;
+                stop.quantity = quantity;
+                if !keep_priority {
+                    stop.seq = self.next_seq();
+                }
+                info!(
+                    "Account {} replaced stop {} for security {} with {} shares triggered at {}",
+                    acc.0, order.0, sec_id.0, quantity, price
+                );
+                return Ok(keep_priority);
+            }
+        }
+        error!(
+            "Account {} attempted to replace nonexistent order {}",
+            acc.0, order.0
+        );
+        Err(MarketError::OrderDoesNotExist(order))
+    }
+
+    /// Trading in a security over the current session
+    pub fn security_stats(&self, sec_id: SecId) -> Result<SessionStats, MarketError> {
+        if let Some(mut sec) = self.securities.get_mut(&sec_id) {
+            let now = SystemTime::now();
+            sec.stats.roll_if_due(now, self.next_session_close(now));
+            debug!(
+                "Security {} traded {} shares in {} trades this session",
+                sec_id.0, sec.stats.volume, sec.stats.trades
+            );
+            Ok(sec.stats.clone())
+        } else {
+            error!(
+                "Attempted to look up statistics of nonexistent security {}",
+                sec_id.0
+            );
+            Err(MarketError::SecDoesNotExist(sec_id))
+        }
+    }
+
+    /// Candles of a security starting within `from..to`, oldest first
+    pub fn candles(
+        &self,
+        sec_id: SecId,
+        interval: CandleInterval,
+        from: Option<SystemTime>,
+        to: Option<SystemTime>,
+    ) -> Result<Vec<Candle>, MarketError> {
+        if let Some(sec) = self.securities.get(&sec_id) {
+            let candles = sec.candles.range(interval, from, to);
+            debug!(
+                "Found {} {:?} candles of security {}",
+                candles.len(),
+                interval,
+                sec_id.0
+            );
+            Ok(candles)
+        } else {
+            error!(
+                "Attempted to look up candles of nonexistent security {}",
+                sec_id.0
+            );
+            Err(MarketError::SecDoesNotExist(sec_id))
+        }
+    }
+
+    /// Trades of a security, newest first, optionally only those of one account
+    pub fn trades(
+        &self,
+        sec_id: SecId,
+        acc_id: Option<AccId>,
+        limit: usize,
+    ) -> Result<Vec<Trade>, MarketError> {
+        if let Some(sec) = self.securities.get(&sec_id) {
+            let trades: Vec<_> = sec
+                .trades
+                .iter()
+                .rev()
+                .filter(|t| acc_id.is_none_or(|acc| t.buyer == acc || t.seller == acc))
+                .take(limit)
+                .cloned()
+                .collect();
+            debug!(
+                "Found {} trades of security {} for account {:?}",
+                trades.len(),
+                sec_id.0,
+                acc_id.map(|a| a.0)
+            );
+            Ok(trades)
+        } else {
+            error!(
+                "Attempted to look up trade history of nonexistent security {}",
+                sec_id.0
+            );
+            Err(MarketError::SecDoesNotExist(sec_id))
+        }
+    }
+
+    /// Splits every `from` shares of a security into `to` shares, or merges them if
+    /// `to` is smaller. Holdings, the book, prices and candles are restated together
+    /// while the security is locked. Fractions of a share left over are settled in
+    /// cash at the new price. Futures series on the security are quoted and settled
+    /// in its shares as they were, so it can not split while any are listed. Options,
+    /// futures and baskets can not be split themselves.
+    pub fn split(
+        &self,
+        sec_id: SecId,
+        to: usize,
+        from: usize,
+    ) -> Result<CorporateAction, MarketError> {
+        let invalid = || {
+            error!(
+                "Rejected split of security {} from {} into {} shares",
+                sec_id.0, from, to
+            );
+            MarketError::InvalidSplit { to, from }
+        };
+        if to == 0 || from == 0 || to == from || to > MAX_SPLIT_RATIO || from > MAX_SPLIT_RATIO {
+            return Err(invalid());
+        }
+        let futures = self.securities.iter().find(|other| {
+            other.delisted.is_none()
+                && other
+                    .future
+                    .as_ref()
+                    .is_some_and(|f| f.underlying == Underlying::Security(sec_id))
+        });
+        if let Some(future) = futures.map(|future| *future.key()) {
+            error!(
+                "Rejected split of security {} while futures series {} on it is listed",
+                sec_id.0, future.0
+            );
+            return Err(MarketError::FuturesListed {
+                sec: sec_id,
+                future,
+            });
+        }
+        // Everything restated is checked before anything is, so a split that can not
+        // be held leaves the market as it was
+        let derivatives_fit = self.securities.iter().all(|other| {
+            let basket = other
+                .basket
+                .as_ref()
+                .is_none_or(|b| b.can_split(sec_id, to));
+            let option = other
+                .option
+                .filter(|o| o.underlying == sec_id)
+                .is_none_or(|o| o.contract_size.checked_mul(to).is_some());
+            basket && option
+        });
+        if !derivatives_fit {
+            return Err(invalid());
+        }
+        let Some(mut sec) = self.securities.get_mut(&sec_id) else {
+            error!("Attempted to split nonexistent security {}", sec_id.0);
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        sec.check_listed(sec_id)?;
+        // Derivatives and baskets are split through what they are made of, their own
+        // terms being restated along with it
+        if sec.is_derivative() || sec.basket.is_some() || !sec.can_split(to, from) {
+            return Err(invalid());
+        }
+
+        // Fractions of a share left over are bought by the issuer at the new price
+        let price = sec.last_trade.scale_by(from as f64 / to as f64);
+        let mut restated = Vec::new();
+        let mut outstanding = 0i64;
+        let mut in_lieu = Money::ZERO;
+        for account in self.accounts.iter() {
+            let Some(shares) = account.holdings.get(&sec_id).copied() else {
+                continue;
+            };
+            let Some(scaled) = shares.checked_mul(to as i64) else {
+                return Err(invalid());
+            };
+            let split = scaled / from as i64;
+            let cash = price.scale_by((scaled % from as i64) as f64 / from as f64);
+            outstanding = outstanding.checked_add(split).ok_or_else(invalid)?;
+            in_lieu = in_lieu.checked_add(cash).ok_or_else(invalid)?;
+            restated.push((*account.key(), shares, split, cash));
+        }
+        let issuer = sec.issuer;
+        let issuer_cash = self
+            .accounts
+            .get(&issuer)
+            .map_or(Money::ZERO, |account| account.cash(&sec.currency));
+        if issuer_cash < in_lieu {
+            error!(
+                "Issuer account {} with {} can not pay {} in lieu of fractional shares of security {}",
+                issuer.0, issuer_cash, in_lieu, sec_id.0
+            );
+            return Err(MarketError::InsufficientCash {
+                acc: issuer,
+                cash: issuer_cash,
+                required: in_lieu,
+            });
+        }
+
+        let now = SystemTime::now();
+        sec.split(sec_id, to, from, &self.executions);
+        for (acc_id, shares, split, cash) in restated {
+            let Some(mut account) = self.accounts.get_mut(&acc_id) else {
+                continue;
+            };
+            info!(
+                "Account {} went from {} to {} shares of security {} in a split, settling {} in cash",
+                acc_id.0, shares, split, sec_id.0, cash
+            );
+            account.holdings.insert(sec_id, split);
+            if cash != Money::ZERO {
+                let entry = account.post(
+                    EntryKind::CashInLieu { sec: sec_id },
+                    &sec.currency,
+                    cash,
+                    now,
+                );
+                let _ = self.statements.send((acc_id, entry));
+            }
+        }
+        if in_lieu != Money::ZERO {
+            if let Some(mut account) = self.accounts.get_mut(&issuer) {
+                let entry = account.post(
+                    EntryKind::CashInLieu { sec: sec_id },
+                    &sec.currency,
+                    -in_lieu,
+                    now,
+                );
+                info!(
+                    "Issuer account {} paid {} in lieu of fractional shares of security {}",
+                    issuer.0, in_lieu, sec_id.0
+                );
+                let _ = self.statements.send((issuer, entry));
+            }
+        }
+        sec.shares_outstanding = outstanding.max(0) as usize;
+        drop(sec);
+
+        for mut other in self.securities.iter_mut() {
+            let (other_id, other) = other.pair_mut();
+            if let Some(basket) = other.basket.as_mut().filter(|b| b.contains(sec_id)) {
+                basket.split(sec_id, to, from);
+                info!(
+                    "Restated units of basket security {} after split of {}",
+                    other_id.0, sec_id.0
+                );
+            }
+            if let Some(option) = other.option.as_mut().filter(|o| o.underlying == sec_id) {
+                option.split(to, from);
+                info!(
+                    "Restated option series {} after split of {} to a strike of {} for {} shares",
+                    other_id.0, sec_id.0, option.strike, option.contract_size
+                );
+            }
+        }
+
+        let quotes = quotes(&self.securities, &self.rates());
+        for mut index in self.indices.iter_mut() {
+            index.split(sec_id, from as f64 / to as f64, &quotes);
+        }
+
+        let action = CorporateAction {
+            sec: sec_id,
+            time: now,
+            kind: ActionKind::Split { to, from },
+        };
+        info!(
+            "Security {} split from {} into {} shares, last trading at {}",
+            sec_id.0, from, to, price
+        );
+        let _ = self.corporate_actions.send(action);
+        Ok(action)
+    }
+
+    /// Mints `quantity` new shares of a security in the account of its issuer,
+    /// offering them on the book at `price` if given. Returns the order placed.
+    pub fn issue_shares(
+        &self,
+        sec_id: SecId,
+        quantity: usize,
+        price: Option<Money>,
+    ) -> Result<(CorporateAction, Option<OrderId>), MarketError> {
+        let Some(issuer) = self.securities.get(&sec_id).map(|sec| sec.issuer) else {
+            error!(
+                "Attempted to issue shares of nonexistent security {}",
+                sec_id.0
+            );
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        // The shares are offered like any other ask of the issuer
+        if let Some(price) = price {
+            let (value, currency) = self.validate_order(sec_id, price, quantity, None)?;
+            self.check_risk(OrderRequest {
+                account: issuer,
+                sec: sec_id,
+                side: Side::Sell,
+                price,
+                quantity,
+                value,
+                currency,
+            })?;
+        }
+        let Some(mut sec) = self.securities.get_mut(&sec_id) else {
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        sec.check_listed(sec_id)?;
+        sec.check_quantity(sec_id, quantity)?;
+        let ticks = match price {
+            Some(price) => {
+                sec.check_order_price(sec_id, price)?;
+                Some(sec.price_to_ticks(sec_id, price)?)
+            }
+            None => None,
+        };
+
+        let Some(mut account) = self.accounts.get_mut(&issuer) else {
+            return Err(MarketError::AccDoesNotExist(issuer));
+        };
+        let outstanding = sec
+            .shares_outstanding
+            .checked_add(quantity)
+            .filter(|outstanding| sec.notional(sec.last_trade, *outstanding).is_some());
+        let held = i64::try_from(quantity)
+            .ok()
+            .and_then(|quantity| account.shares(sec_id).checked_add(quantity));
+        let (Some(outstanding), Some(held)) = (outstanding, held) else {
+            error!(
+                "Issuer account {} attempted to issue {} shares of security {}, more than can be held",
+                issuer.0, quantity, sec_id.0
+            );
+            return Err(MarketError::ValueOverflow {
+                sec: sec_id,
+                price: sec.last_trade,
+                quantity,
+            });
+        };
+        account.holdings.insert(sec_id, held);
+        drop(account);
+        sec.shares_outstanding = outstanding;
+        let order = ticks.map(|ticks| {
+            let ask = Ask::new(issuer, ticks, quantity, self.next_seq());
+            let id = ask.id;
+            let _ = self.executions.send(Execution::of_ask(
+                sec_id,
+                &ask,
+                ExecKind::Placed,
+                price.unwrap_or_default(),
+                quantity,
+            ));
+            sec.asks.push(ask);
+            id
+        });
+        info!(
+            "Issuer account {} issued {} shares of security {}, leaving {} outstanding",
+            issuer.0, quantity, sec_id.0, sec.shares_outstanding
+        );
+        drop(sec);
+        self.rebase_indices();
+
+        let action = CorporateAction {
+            sec: sec_id,
+            time: SystemTime::now(),
+            kind: ActionKind::Issue { quantity, price },
+        };
+        let _ = self.corporate_actions.send(action);
+        Ok((action, order))
+    }
+
+    /// Retires `quantity` shares of a security held by its issuer, or if `price` is
+    /// given, places a bid of the issuer retiring the shares as they are bought.
+    /// Returns the order placed.
+    pub fn buyback_shares(
+        &self,
+        sec_id: SecId,
+        quantity: usize,
+        price: Option<Money>,
+    ) -> Result<(CorporateAction, Option<OrderId>), MarketError> {
+        let Some(issuer) = self.securities.get(&sec_id).map(|sec| sec.issuer) else {
+            error!(
+                "Attempted to buy back shares of nonexistent security {}",
+                sec_id.0
+            );
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        // The bid is checked like any other bid of the issuer
+        if let Some(price) = price {
+            self.check_cash(issuer, sec_id, price, quantity)?;
+            let (value, currency) = self.validate_order(sec_id, price, quantity, None)?;
+            self.check_risk(OrderRequest {
+                account: issuer,
+                sec: sec_id,
+                side: Side::Buy,
+                price,
+                quantity,
+                value,
+                currency,
+            })?;
+        }
+        let Some(mut sec) = self.securities.get_mut(&sec_id) else {
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        sec.check_listed(sec_id)?;
+        sec.check_quantity(sec_id, quantity)?;
+
+        let order = if let Some(price) = price {
+            sec.check_order_price(sec_id, price)?;
+            let ticks = sec.price_to_ticks(sec_id, price)?;
+            let bid = Bid::new(issuer, ticks, quantity, self.next_seq());
+            let id = bid.id;
+            let _ = self.executions.send(Execution::of_bid(
+                sec_id,
+                &bid,
+                ExecKind::Placed,
+                price,
+                quantity,
+            ));
+            sec.bids.push(bid);
+            sec.buybacks.insert(id);
+            info!(
+                "Issuer account {} placed bid {} to buy back {} shares of security {} at max price of {}",
+                issuer.0, id.0, quantity, sec_id.0, price
+            );
+            Some(id)
+        } else {
+            let Some(mut account) = self.accounts.get_mut(&issuer) else {
+                return Err(MarketError::AccDoesNotExist(issuer));
+            };
+            let held = account.shares(sec_id);
+            if usize::try_from(held).unwrap_or(0) < quantity {
+                error!(
+                    "Issuer account {} holding {} shares of security {} attempted to retire {}",
+                    issuer.0, held, sec_id.0, quantity
+                );
+                return Err(MarketError::InsufficientShares {
+                    acc: issuer,
+                    sec: sec_id,
+                    held,
+                    quantity,
+                });
+            }
+            account.holdings.insert(sec_id, held - quantity as i64);
+            sec.shares_outstanding = sec.shares_outstanding.saturating_sub(quantity);
+            info!(
+                "Issuer account {} retired {} shares of security {}, leaving {} outstanding",
+                issuer.0, quantity, sec_id.0, sec.shares_outstanding
+            );
+            None
+        };
+        drop(sec);
+        if order.is_none() {
+            self.rebase_indices();
+        }
+
+        let action = CorporateAction {
+            sec: sec_id,
+            time: SystemTime::now(),
+            kind: ActionKind::Buyback { quantity, price },
+        };
+        let _ = self.corporate_actions.send(action);
+        Ok((action, order))
+    }
+
+    /// Declares a cash dividend of `per_share` on a security, paid at `pay` to the
+    /// accounts holding it at `record`. Short positions are charged the dividend.
+    pub fn declare_dividend(
+        &self,
+        sec_id: SecId,
+        per_share: Money,
+        record: SystemTime,
+        pay: SystemTime,
+    ) -> Result<CorporateAction, MarketError> {
+        let now = SystemTime::now();
+        if !per_share.is_positive() || record < now || pay < record {
+            error!(
+                "Rejected dividend of {} per share of security {} recorded at {:?} and paid at {:?}",
+                per_share, sec_id.0, record, pay
+            );
+            return Err(MarketError::InvalidDividend {
+                sec: sec_id,
+                per_share,
+                record,
+                pay,
+            });
+        }
+        let Some(mut sec) = self.securities.get_mut(&sec_id) else {
+            error!(
+                "Attempted to declare dividend on nonexistent security {}",
+                sec_id.0
+            );
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        sec.check_listed(sec_id)?;
+        // Paid by the issuer on the shares held by everyone else
+        let issuer_cash = self
+            .accounts
+            .get(&sec.issuer)
+            .map(|account| (account.shares(sec_id), account.cash(&sec.currency)));
+        let Some((issuer_shares, issuer_cash)) = issuer_cash else {
+            return Err(MarketError::AccDoesNotExist(sec.issuer));
+        };
+        let held = (sec.shares_outstanding as i64 - issuer_shares).max(0);
+        let Some(required) = per_share.checked_mul(held) else {
+            error!(
+                "Dividend of {} per share on {} shares of security {} is worth too much to pay",
+                per_share, held, sec_id.0
+            );
+            return Err(MarketError::ValueOverflow {
+                sec: sec_id,
+                price: per_share,
+                quantity: held as usize,
+            });
+        };
+        if issuer_cash < required {
+            error!(
+                "Issuer account {} with {} can not pay {} in dividends on security {}",
+                sec.issuer.0, issuer_cash, required, sec_id.0
+            );
+            return Err(MarketError::InsufficientCash {
+                acc: sec.issuer,
+                cash: issuer_cash,
+                required,
+            });
+        }
+        sec.dividends.push(Dividend {
+            per_share,
+            record,
+            pay,
+            holders: None,
+        });
+        drop(sec);
+
+        let action = CorporateAction {
+            sec: sec_id,
+            time: now,
+            kind: ActionKind::Dividend {
+                per_share,
+                record,
+                pay,
+            },
+        };
+        info!(
+            "Declared dividend of {} per share of security {} recorded at {:?} and paid at {:?}",
+            per_share, sec_id.0, record, pay
+        );
+        let _ = self.corporate_actions.send(action);
+        Ok(action)
+    }
+
+    /// Records the holders of dividends whose record time has passed, and pays those
+    /// that are due out of the cash of the issuer. Dividends the issuer can not cover
+    /// stay pending until it can. Short positions are charged what cash they hold, the
+    /// rest being owed on the borrow like its fees. Returns the number of dividends
+    /// paid.
+    pub fn pay_dividends(&self) -> usize {
+        let now = SystemTime::now();
+        let mut paid = 0;
+        for mut sec in self.securities.iter_mut() {
+            let (sec_id, sec) = sec.pair_mut();
+            sec.record_dividends(*sec_id, &self.accounts, now);
+
+            let (due, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut sec.dividends)
+                .into_iter()
+                .partition(|dividend| dividend.holders.is_some() && now >= dividend.pay);
+            sec.dividends = pending;
+            for dividend in due {
+                let holders = dividend.holders.as_deref().unwrap_or_default();
+                let amounts: Option<Vec<_>> = holders
+                    .iter()
+                    .map(|(acc_id, shares)| {
+                        Some((*acc_id, *shares, dividend.per_share.checked_mul(*shares)?))
+                    })
+                    .collect();
+                // The issuer covers the long holders even if the short ones pay nothing
+                let total = amounts.as_ref().and_then(|amounts| {
+                    amounts
+                        .iter()
+                        .filter(|(_, shares, _)| *shares > 0)
+                        .try_fold(Money::ZERO, |total, (_, _, amount)| {
+                            total.checked_add(*amount)
+                        })
+                });
+                let issuer_cash = self
+                    .accounts
+                    .get(&sec.issuer)
+                    .map(|account| account.cash(&sec.currency));
+                let (Some(amounts), Some(total), Some(issuer_cash)) = (amounts, total, issuer_cash)
+                else {
+                    error!(
+                        "Dividend of {} per share of security {} is worth too much to pay",
+                        dividend.per_share, sec_id.0
+                    );
+                    sec.dividends.push(dividend);
+                    continue;
+                };
+                if issuer_cash < total {
+                    warn!(
+                        "Issuer account {} with {} can not yet pay {} in dividends on security {}",
+                        sec.issuer.0, issuer_cash, total, sec_id.0
+                    );
+                    sec.dividends.push(dividend);
+                    continue;
+                }
+
+                let mut shares_paid = 0i64;
+                let mut collected = Money::ZERO;
+                for (acc_id, shares, amount) in amounts {
+                    let Some(mut account) = self.accounts.get_mut(&acc_id) else {
+                        continue;
+                    };
+                    let amount = if shares < 0 {
+                        let charged = -amount;
+                        let paid = charged.min(account.cash(&sec.currency).max(Money::ZERO));
+                        let unpaid = charged - paid;
+                        if unpaid.is_positive() {
+                            if let Some(borrow) = account.borrows.get_mut(sec_id) {
+                                borrow.owed = borrow.owed.saturating_add(unpaid);
+                                warn!(
+                                    "Account {} owes {} in dividends on its short position in security {}",
+                                    acc_id.0, unpaid, sec_id.0
+                                );
+                            } else {
+                                warn!(
+                                    "Account {} closed its short position in security {} without paying {} in dividends on it",
+                                    acc_id.0, sec_id.0, unpaid
+                                );
+                            }
+                        }
+                        collected += paid;
+                        -paid
+                    } else {
+                        amount
+                    };
+                    let kind = EntryKind::Dividend {
+                        sec: *sec_id,
+                        shares,
+                        per_share: dividend.per_share,
+                    };
+                    let entry = account.post(kind, &sec.currency, amount, now);
+                    debug!(
+                        "Paid {} to account {} in dividends on {} shares of security {}",
+                        entry.amount, acc_id.0, shares, sec_id.0
+                    );
+                    shares_paid = shares_paid.saturating_add(shares.max(0));
+                    let _ = self.statements.send((acc_id, entry));
+                }
+                if let Some(mut account) = self.accounts.get_mut(&sec.issuer) {
+                    let kind = EntryKind::Dividend {
+                        sec: *sec_id,
+                        shares: -shares_paid,
+                        per_share: dividend.per_share,
+                    };
+                    let net = total - collected;
+                    let entry = account.post(kind, &sec.currency, -net, now);
+                    info!(
+                        "Issuer account {} paid {} in dividends on security {}",
+                        sec.issuer.0, net, sec_id.0
+                    );
+                    let _ = self.statements.send((sec.issuer, entry));
+                }
+                paid += 1;
+            }
+        }
+        paid
+    }
+
+    /// Latest cash paid into or out of an account other than by trading, oldest first
+    pub fn statement(&self, acc_id: AccId) -> Result<Vec<StatementEntry>, MarketError> {
+        if let Some(account) = self.accounts.get(&acc_id) {
+            debug!(
+                "Account {} has {} statement entries",
+                acc_id.0,
+                account.statement.len()
+            );
+            Ok(account.statement.iter().cloned().collect())
+        } else {
+            error!(
+                "Attempted to get statement of nonexistent account {}",
+                acc_id.0
+            );
+            Err(MarketError::AccDoesNotExist(acc_id))
+        }
+    }
+
+    /// Ends trading in a security for good, canceling its orders and, if `price` is
+    /// given, closing every position at it out of the cash of the issuer. Its trades,
+    /// candles and statistics stay queryable.
+    pub fn delist(
+        &self,
+        sec_id: SecId,
+        price: Option<Money>,
+    ) -> Result<CorporateAction, MarketError> {
+        if let Some(price) = price.filter(|price| !price.is_positive()) {
+            error!(
+                "Rejected final price {} for delisting security {}",
+                price, sec_id.0
+            );
+            return Err(MarketError::InvalidPrice { sec: sec_id, price });
+        }
+        let Some(mut sec) = self.securities.get_mut(&sec_id) else {
+            error!("Attempted to delist nonexistent security {}", sec_id.0);
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        sec.check_listed(sec_id)?;
+
+        // Positions are closed at the final price by the issuer, which must cover the
+        // shares held by everyone else
+        let issuer = sec.issuer;
+        let mut cash_outs = Vec::new();
+        let mut paid = Money::ZERO;
+        let mut shares_paid = 0i64;
+        if let Some(price) = price {
+            for account in self.accounts.iter() {
+                let shares = account.shares(sec_id);
+                if shares == 0 || *account.key() == issuer {
+                    continue;
+                }
+                let amount = price.checked_mul(shares);
+                let total = amount.and_then(|amount| paid.checked_add(amount));
+                let (Some(amount), Some(total)) = (amount, total) else {
+                    error!(
+                        "Cash out of security {} at {} is worth too much to pay",
+                        sec_id.0, price
+                    );
+                    return Err(MarketError::ValueOverflow {
+                        sec: sec_id,
+                        price,
+                        quantity: shares.unsigned_abs() as usize,
+                    });
+                };
+                paid = total;
+                shares_paid = shares_paid.saturating_add(shares);
+                cash_outs.push((*account.key(), shares, amount));
+            }
+            let issuer_cash = self
+                .accounts
+                .get(&issuer)
+                .map_or(Money::ZERO, |account| account.cash(&sec.currency));
+            if issuer_cash < paid {
+                error!(
+                    "Issuer account {} with {} can not pay {} to cash out security {}",
+                    issuer.0, issuer_cash, paid, sec_id.0
+                );
+                return Err(MarketError::InsufficientCash {
+                    acc: issuer,
+                    cash: issuer_cash,
+                    required: paid,
+                });
+            }
+        }
+
+        let now = SystemTime::now();
+        sec.delisted = Some(now);
+        sec.cancel_orders(sec_id, ExecKind::Delisted, &self.executions);
+        sec.borrowers.clear();
+        for mut account in self.accounts.iter_mut() {
+            account.liquidations.remove(&sec_id);
+            if price.is_some() {
+                sec.accrue_borrow_fee(sec_id, &mut account, Instant::now());
+                account.borrows.remove(&sec_id);
+                account.holdings.remove(&sec_id);
+            }
+        }
+        if let Some(price) = price {
+            for (acc_id, shares, amount) in cash_outs {
+                let Some(mut account) = self.accounts.get_mut(&acc_id) else {
+                    continue;
+                };
+                let kind = EntryKind::CashOut {
+                    sec: sec_id,
+                    shares,
+                    price,
+                };
+                let entry = account.post(kind, &sec.currency, amount, now);
+                info!(
+                    "Account {} was cashed out of {} shares of delisted security {} for {}",
+                    acc_id.0, shares, sec_id.0, entry.amount
+                );
+                let _ = self.statements.send((acc_id, entry));
+            }
+            if paid != Money::ZERO {
+                if let Some(mut account) = self.accounts.get_mut(&issuer) {
+                    let kind = EntryKind::CashOut {
+                        sec: sec_id,
+                        shares: -shares_paid,
+                        price,
+                    };
+                    let entry = account.post(kind, &sec.currency, -paid, now);
+                    info!(
+                        "Issuer account {} paid {} to cash out security {}",
+                        issuer.0, paid, sec_id.0
+                    );
+                    let _ = self.statements.send((issuer, entry));
+                }
+            }
+            sec.shares_outstanding = 0;
+        }
+        drop(sec);
+        self.recalculate_indices();
+
+        let action = CorporateAction {
+            sec: sec_id,
+            time: now,
+            kind: ActionKind::Delisting { price },
+        };
+        info!(
+            "Security {} delisted with final price {:?}",
+            sec_id.0, price
+        );
+        let _ = self.corporate_actions.send(action);
+        Ok(action)
+    }
+
+    fn check_basket(
+        &self,
+        basket: &Basket,
+        founding_shares: usize,
+        lot_size: usize,
+    ) -> Result<(), MarketError> {
+        let invalid = |reason: String| {
+            error!("Attempted to create basket security: {}", reason);
+            Err(MarketError::InvalidListing(reason))
+        };
+        if founding_shares > 0 {
+            return invalid("Basket shares are only created from their components".to_string());
+        }
+        if basket.unit_size == 0 || !basket.unit_size.is_multiple_of(lot_size) {
+            return invalid(format!(
+                "Unit of {} shares is not a whole number of lots of {}",
+                basket.unit_size, lot_size
+            ));
+        }
+        if basket.components.is_empty() {
+            return invalid("Basket has no components".to_string());
+        }
+        for (sec_id, quantity) in &basket.components {
+            if *quantity == 0 {
+                return invalid(format!("No shares of component {} in a unit", sec_id.0));
+            }
+            match self.securities.get(sec_id) {
+                Some(sec) if sec.delisted.is_none() => {}
+                Some(_) => return invalid(format!("Component {} is delisted", sec_id.0)),
+                None => return invalid(format!("Component {} does not exist", sec_id.0)),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_option(
+        &self,
+        option: &OptionContract,
+        founding_shares: usize,
+        config: &SecurityConfig,
+    ) -> Result<(), MarketError> {
+        let invalid = |reason: String| {
+            error!("Attempted to list option series: {}", reason);
+            Err(MarketError::InvalidListing(reason))
+        };
+        if founding_shares > 0 {
+            return invalid("Option contracts are only created by writing them".to_string());
+        }
+        if config.basket.is_some() {
+            return invalid("Option series can not also be a basket".to_string());
+        }
+        if config.short_selling.is_some() {
+            return invalid("Option contracts are written, not borrowed".to_string());
+        }
+        if !option.strike.is_positive() {
+            return invalid(format!("Strike {} is not a positive amount", option.strike));
+        }
+        if option.contract_size == 0 {
+            return invalid("Contracts must be for at least one share".to_string());
+        }
+        if option.expiry <= SystemTime::now() {
+            return invalid(format!("Expiry {:?} has passed", option.expiry));
+        }
+        let underlying = option.underlying;
+        match self.securities.get(&underlying) {
+            Some(sec) if sec.is_derivative() => {
+                invalid(format!("Underlying {} is a derivative", underlying.0))
+            }
+            Some(sec) if sec.delisted.is_some() => {
+                invalid(format!("Underlying {} is delisted", underlying.0))
+            }
+            Some(sec) if sec.currency != config.currency => invalid(format!(
+                "Underlying {} is in {}, not {}",
+                underlying.0, sec.currency, config.currency
+            )),
+            Some(_) => Ok(()),
+            None => invalid(format!("Underlying {} does not exist", underlying.0)),
+        }
+    }
+
+    fn check_future(
+        &self,
+        future: &FutureContract,
+        founding_shares: usize,
+        config: &SecurityConfig,
+    ) -> Result<(), MarketError> {
+        let invalid = |reason: String| {
+            error!("Attempted to list futures series: {}", reason);
+            Err(MarketError::InvalidListing(reason))
+        };
+        if founding_shares > 0 {
+            return invalid("Futures contracts are only created by trading them".to_string());
+        }
+        if config.basket.is_some() || config.option.is_some() {
+            return invalid("Futures series can not also be a basket or options".to_string());
+        }
+        if config.short_selling.is_some() {
+            return invalid("Futures contracts are sold, not borrowed".to_string());
+        }
+        if future.multiplier == 0 {
+            return invalid("Contracts must be for at least one unit".to_string());
+        }
+        if !future.maintenance_margin.is_positive()
+            || future.maintenance_margin > future.initial_margin
+        {
+            return invalid(format!(
+                "Maintenance margin {} is not positive and at most the initial margin {}",
+                future.maintenance_margin, future.initial_margin
+            ));
+        }
+        if future.expiry <= SystemTime::now() {
+            return invalid(format!("Expiry {:?} has passed", future.expiry));
+        }
+        match &future.underlying {
+            Underlying::Security(sec_id) => match self.securities.get(sec_id) {
+                Some(sec) if sec.is_derivative() => {
+                    invalid(format!("Underlying {} is a derivative", sec_id.0))
+                }
+                Some(sec) if sec.delisted.is_some() => {
+                    invalid(format!("Underlying {} is delisted", sec_id.0))
+                }
+                Some(sec) if sec.currency != config.currency => invalid(format!(
+                    "Underlying {} is in {}, not {}",
+                    sec_id.0, sec.currency, config.currency
+                )),
+                Some(_) => Ok(()),
+                None => invalid(format!("Underlying {} does not exist", sec_id.0)),
+            },
+            Underlying::Index(name) if !self.indices.contains_key(name) => {
+                invalid(format!("Underlying index {} does not exist", name))
+            }
+            // Indices are valued in the base currency
+            Underlying::Index(name) if config.currency != Currency::default() => invalid(format!(
+                "Underlying index {} is in {}",
+                name,
+                Currency::default()
+            )),
+            Underlying::Index(_) => Ok(()),
+        }
+    }
+
+    /// Checks that an account holds the initial margin on every futures contract it
+    /// would have if an order for `quantity` contracts filled
+    fn check_futures_margin(
+        &self,
+        acc_id: AccId,
+        sec_id: SecId,
+        side: Side,
+        quantity: usize,
+    ) -> Result<(), MarketError> {
+        if self
+            .securities
+            .get(&sec_id)
+            .is_none_or(|sec| sec.future.is_none())
+        {
+            return Ok(());
+        }
+        let rates = self.rates();
+        let Some((holdings, cash, currency)) = self.accounts.get(&acc_id).map(|account| {
+            (
+                account.holdings.clone(),
+                account.cash_value(&rates),
+                account.currency.clone(),
+            )
+        }) else {
+            return Ok(());
+        };
+        let overflow = || {
+            error!(
+                "Futures position of account {} after {:?} order of {} contracts of series {} is more than can be held",
+                acc_id.0, side, quantity, sec_id.0
+            );
+            MarketError::QuantityOverflow {
+                sec: sec_id,
+                quantity,
+            }
+        };
+        let contracts = holdings.get(&sec_id).copied().unwrap_or(0);
+        let change = i64::try_from(quantity).map_err(|_| overflow())?;
+        let after = match side {
+            Side::Buy => contracts.checked_add(change),
+            Side::Sell => contracts.checked_sub(change),
+        }
+        .ok_or_else(overflow)?;
+        let mut required = Money::ZERO;
+        let positions = holdings
+            .iter()
+            .filter(|(held, _)| **held != sec_id)
+            .chain([(&sec_id, &after)]);
+        for (held, contracts) in positions {
+            let Some((margin, held_currency)) = self.securities.get(held).and_then(|sec| {
+                let future = sec.future.as_ref().filter(|_| sec.delisted.is_none())?;
+                Some((future.initial_margin, sec.currency.clone()))
+            }) else {
+                continue;
+            };
+            // A margin too large to hold can never be covered
+            let Some(margin) = contracts
+                .checked_abs()
+                .and_then(|contracts| margin.checked_mul(contracts))
+            else {
+                required = Money::MAX;
+                break;
+            };
+            required = required.saturating_add(self.convert(margin, &held_currency, &currency)?);
+        }
+        if cash < required && after.unsigned_abs() > contracts.unsigned_abs() {
+            error!(
+                "Account {} with cash {} can not cover initial margin of {} for {:?} order of {} contracts of futures series {}",
+                acc_id.0, cash, required, side, quantity, sec_id.0
+            );
+            return Err(MarketError::InsufficientMargin {
+                acc: acc_id,
+                equity: cash,
+                required,
+            });
+        }
+        Ok(())
+    }
+
+    /// Price a futures series is finally settled at, None while the underlying has
+    /// no value
+    fn underlying_price(&self, underlying: &Underlying) -> Option<Money> {
+        match underlying {
+            Underlying::Security(sec_id) => Some(self.securities.get(sec_id)?.last_trade),
+            Underlying::Index(name) => {
+                let value = self.indices.get(name)?.value;
+                Some(Money::from_f64(value))
+            }
+        }
+    }
+
+    /// Marks futures positions to the last trade of their series as each session
+    /// closes, paying the change in value since the last settlement in cash, and at
+    /// expiry settles them for good at the price of the underlying. Returns the
+    /// number of series settled.
+    pub fn settle_futures(&self) -> usize {
+        let now = SystemTime::now();
+        let due: Vec<_> = self
+            .securities
+            .iter()
+            .filter(|sec| sec.delisted.is_none())
+            .filter_map(|sec| {
+                let future = sec.future.clone()?;
+                let settles = sec.settles.is_some_and(|settles| now >= settles);
+                (settles || now >= future.expiry).then(|| (*sec.key(), future))
+            })
+            .collect();
+
+        for (sec_id, future) in &due {
+            let expired = now >= future.expiry;
+            let final_price = if expired {
+                let price = self.underlying_price(&future.underlying);
+                if price.is_none() {
+                    warn!(
+                        "Underlying of futures series {} has no price, settling it at its last trade",
+                        sec_id.0
+                    );
+                }
+                price
+            } else {
+                None
+            };
+            let Some(mut sec) = self.securities.get_mut(sec_id) else {
+                continue;
+            };
+            let price = final_price.unwrap_or(sec.last_trade);
+            let from = sec.settlement;
+            if expired {
+                sec.delisted = Some(now);
+                sec.cancel_orders(*sec_id, ExecKind::Expired, &self.executions);
+            }
+            for mut account in self.accounts.iter_mut() {
+                let (acc_id, account) = account.pair_mut();
+                let contracts = account.shares(*sec_id);
+                if expired {
+                    account.holdings.remove(sec_id);
+                    account.borrows.remove(sec_id);
+                    account.liquidations.remove(sec_id);
+                }
+                let Some(amount) = future.variation(contracts, from, price) else {
+                    error!(
+                        "Variation margin of account {} on {} contracts of futures series {} is too large",
+                        acc_id.0, contracts, sec_id.0
+                    );
+                    continue;
+                };
+                if amount == Money::ZERO {
+                    continue;
+                }
+                let kind = EntryKind::VariationMargin {
+                    sec: *sec_id,
+                    contracts,
+                    price,
+                };
+                let entry = account.post(kind, &sec.currency, amount, now);
+                debug!(
+                    "Paid {} to account {} in variation margin on {} contracts of futures series {}",
+                    amount, acc_id.0, contracts, sec_id.0
+                );
+                let _ = self.statements.send((*acc_id, entry));
+            }
+            sec.settlement = price;
+            sec.settles = Some(self.next_session_close(now));
+            info!(
+                "Futures series {} settled at {}, moving {} from {}",
+                sec_id.0,
+                price,
+                price - from,
+                from
+            );
+            drop(sec);
+
+            if expired {
+                let action = CorporateAction {
+                    sec: *sec_id,
+                    time: now,
+                    kind: ActionKind::FinalSettlement { price },
+                };
+                info!(
+                    "Futures series {} expired with a final settlement price of {}",
+                    sec_id.0, price
+                );
+                let _ = self.corporate_actions.send(action);
+            }
+        }
+        due.len()
+    }
+
+    /// Expires option series whose expiry has passed, first exercising those in the
+    /// money at the last trade of their underlying by delivering its shares between
+    /// holders and writers against the strike. Calls whose holders can not pay the
+    /// strike lapse, and writers are assigned the contracts exercised in proportion
+    /// to those they wrote. Returns the number of series expired.
+    pub fn exercise_options(&self) -> usize {
+        let now = SystemTime::now();
+        let due: Vec<_> = self
+            .securities
+            .iter()
+            .filter(|sec| sec.delisted.is_none())
+            .filter_map(|sec| Some((*sec.key(), sec.option?)))
+            .filter(|(_, option)| now >= option.expiry)
+            .collect();
+
+        for (sec_id, option) in &due {
+            let (price, listed) = match self.securities.get(&option.underlying) {
+                Some(underlying) => (underlying.last_trade, underlying.delisted.is_none()),
+                None => (Money::ZERO, false),
+            };
+            let exercised = listed && option.in_the_money(price);
+            let Some(mut sec) = self.securities.get_mut(sec_id) else {
+                continue;
+            };
+            sec.delisted = Some(now);
+            sec.cancel_orders(*sec_id, ExecKind::Expired, &self.executions);
+            let mut shorts = Vec::new();
+            let mut settle = |acc_id: AccId, account: &mut Account, contracts: i64| {
+                let (shares, cost) = option.settlement(contracts);
+                account.adjust_shares(option.underlying, shares, Instant::now());
+                if account.shares(option.underlying) < 0 {
+                    shorts.push(acc_id);
+                }
+                let kind = EntryKind::Exercise {
+                    sec: *sec_id,
+                    contracts,
+                    strike: option.strike,
+                };
+                let entry = account.post(kind, &sec.currency, -cost, now);
+                info!(
+                    "Account {} settled {} contracts of option series {} for {} shares of security {} and {}",
+                    acc_id.0, contracts, sec_id.0, shares, option.underlying.0, entry.amount
+                );
+                let _ = self.statements.send((acc_id, entry));
+            };
+            // Holders settle first, so writers are only assigned what was exercised
+            let mut written = Vec::new();
+            let mut held = 0i64;
+            for mut account in self.accounts.iter_mut() {
+                let (acc_id, account) = account.pair_mut();
+                account.liquidations.remove(sec_id);
+                account.borrows.remove(sec_id);
+                let contracts = account.holdings.remove(sec_id).unwrap_or(0);
+                if contracts == 0 || !exercised {
+                    continue;
+                }
+                if contracts < 0 {
+                    written.push((*acc_id, -contracts));
+                    continue;
+                }
+                let (_, cost) = option.settlement(contracts);
+                let cash = account.cash(&sec.currency);
+                if cash < cost {
+                    warn!(
+                        "Account {} with {} can not pay {} for {} contracts of option series {}; they lapse",
+                        acc_id.0, cash, cost, contracts, sec_id.0
+                    );
+                    continue;
+                }
+                settle(*acc_id, account, contracts);
+                held += contracts;
+            }
+            for (acc_id, contracts) in OptionContract::assign(&written, held) {
+                if contracts == 0 {
+                    continue;
+                }
+                if let Some(mut account) = self.accounts.get_mut(&acc_id) {
+                    settle(acc_id, &mut account, -contracts);
+                }
+            }
+            sec.shares_outstanding = 0;
+            drop(sec);
+            if let Some(mut underlying) = self.securities.get_mut(&option.underlying) {
+                underlying.borrowers.extend(shorts);
+            }
+
+            let action = CorporateAction {
+                sec: *sec_id,
+                time: now,
+                kind: ActionKind::Expiry { price, exercised },
+            };
+            info!(
+                "Option series {} expired with security {} at {}, exercised: {}",
+                sec_id.0, option.underlying.0, price, exercised
+            );
+            let _ = self.corporate_actions.send(action);
+        }
+        due.len()
+    }
+
+    /// Basket of a security and the account holding its components
+    fn basket(&self, sec_id: SecId) -> Result<(Basket, AccId), MarketError> {
+        let Some(sec) = self.securities.get(&sec_id) else {
+            error!("Attempted to use nonexistent basket security {}", sec_id.0);
+            return Err(MarketError::SecDoesNotExist(sec_id));
+        };
+        match &sec.basket {
+            Some(basket) => Ok((basket.clone(), sec.issuer)),
+            None => {
+                error!("Security {} is not a basket", sec_id.0);
+                Err(MarketError::NotABasket(sec_id))
+            }
+        }
+    }
+
+    /// Indicative value of one share of a basket security, from the last trades of
+    /// its components converted to its currency, leaving out components without a
+    /// rate. None for securities that are not baskets.
+    pub fn nav(&self, sec_id: SecId) -> Result<Option<Money>, MarketError> {
+        let (basket, currency) = match self.securities.get(&sec_id) {
+            Some(sec) => (sec.basket.clone(), sec.currency.clone()),
+            None => {
+                error!(
+                    "Attempted to calculate net asset value of nonexistent security {}",
+                    sec_id.0
+                );
+                return Err(MarketError::SecDoesNotExist(sec_id));
+            }
+        };
+        let Some(basket) = basket else {
+            return Ok(None);
+        };
+        let rates = self.rates();
+        let prices: HashMap<_, _> = basket
+            .components
+            .iter()
+            .filter_map(|(component, _)| {
+                let sec = self.securities.get(component)?;
+                let price = rates.convert(sec.last_trade, &sec.currency, &currency)?;
+                Some((*component, price))
+            })
+            .collect();
+        let nav = basket.nav(&prices);
+        debug!(
+            "Basket security {} has a net asset value of {}",
+            sec_id.0, nav
+        );
+        Ok(Some(nav))
+    }
+
+    /// Creates `quantity` shares of a basket security for an account, which hands the
+    /// components of each unit over to the issuer. Returns the shares of the basket
+    /// the account holds.
+    pub fn create_basket_shares(
+        &self,
+        acc_id: AccId,
+        sec_id: SecId,
+        quantity: usize,
+    ) -> Result<i64, MarketError> {
+        let (basket, issuer) = self.basket(sec_id)?;
+        if let Some(sec) = self.securities.get(&sec_id) {
+            sec.check_listed(sec_id)?;
+        }
+        if quantity == 0 || !quantity.is_multiple_of(basket.unit_size) {
+            error!(
+                "Rejected creation of {} shares of basket security {} in units of {}",
+                quantity, sec_id.0, basket.unit_size
+            );
+            return Err(MarketError::OddLot {
+                sec: sec_id,
+                quantity,
+                lot_size: basket.unit_size,
+            });
+        }
+        let units = quantity / basket.unit_size;
+        let overflow = || {
+            error!(
+                "Rejected creation of {} shares of basket security {} as more than can be held",
+                quantity, sec_id.0
+            );
+            MarketError::QuantityOverflow {
+                sec: sec_id,
+                quantity,
+            }
+        };
+        let required = basket.required(units).ok_or_else(overflow)?;
+        let shares = i64::try_from(quantity).map_err(|_| overflow())?;
+        self.securities
+            .get(&sec_id)
+            .and_then(|sec| sec.shares_outstanding.checked_add(quantity))
+            .ok_or_else(overflow)?;
+        // The custodian takes the components in, so it must be able to hold them
+        if let Some(custodian) = self.accounts.get(&issuer) {
+            for (component, required) in required.iter().copied() {
+                if custodian.shares(component).checked_add(required).is_none() {
+                    return Err(overflow());
+                }
+            }
+        }
+
+        let Some(mut account) = self.accounts.get_mut(&acc_id) else {
+            error!(
+                "Nonexistent account {} attempted to create shares of basket security {}",
+                acc_id.0, sec_id.0
+            );
+            return Err(MarketError::AccDoesNotExist(acc_id));
+        };
+        for (component, required) in required.iter().copied() {
+            let held = account.shares(component);
+            if held < required {
+                error!(
+                    "Account {} holding {} shares of security {} cannot deliver {} to create basket shares",
+                    acc_id.0, held, component.0, required
+                );
+                return Err(MarketError::InsufficientShares {
+                    acc: acc_id,
+                    sec: component,
+                    held,
+                    quantity: required as usize,
+                });
+            }
+        }
+        let held = account
+            .shares(sec_id)
+            .checked_add(shares)
+            .ok_or_else(overflow)?;
+        for (component, required) in required.iter().copied() {
+            *account.holdings.entry(component).or_default() -= required;
+        }
+        account.holdings.insert(sec_id, held);
+        drop(account);
+
+        if let Some(mut custodian) = self.accounts.get_mut(&issuer) {
+            for (component, required) in required.iter().copied() {
+                let held = custodian.holdings.entry(component).or_default();
+                *held = held.saturating_add(required);
+            }
+        }
+        if let Some(mut sec) = self.securities.get_mut(&sec_id) {
+            sec.shares_outstanding = sec.shares_outstanding.saturating_add(quantity);
+        }
+        self.rebase_indices();
+        info!(
+            "Account {} created {} shares of basket security {}",
+            acc_id.0, quantity, sec_id.0
+        );
+        Ok(held)
+    }
+
+    /// Redeems `quantity` shares of a basket security held by an account, which
+    /// receives the components of each unit from the issuer. Returns the shares of
+    /// the basket the account holds.
+    pub fn redeem_basket_shares(
+        &self,
+        acc_id: AccId,
+        sec_id: SecId,
+        quantity: usize,
+    ) -> Result<i64, MarketError> {
+        let (basket, issuer) = self.basket(sec_id)?;
+        if quantity == 0 || !quantity.is_multiple_of(basket.unit_size) {
+            error!(
+                "Rejected redemption of {} shares of basket security {} in units of {}",
+                quantity, sec_id.0, basket.unit_size
+            );
+            return Err(MarketError::OddLot {
+                sec: sec_id,
+                quantity,
+                lot_size: basket.unit_size,
+            });
+        }
+        let units = quantity / basket.unit_size;
+        let overflow = || {
+            error!(
+                "Rejected redemption of {} shares of basket security {} as more than can be held",
+                quantity, sec_id.0
+            );
+            MarketError::QuantityOverflow {
+                sec: sec_id,
+                quantity,
+            }
+        };
+        let required = basket.required(units).ok_or_else(overflow)?;
+        let shares = i64::try_from(quantity).map_err(|_| overflow())?;
+
+        let Some(mut account) = self.accounts.get_mut(&acc_id) else {
+            error!(
+                "Nonexistent account {} attempted to redeem shares of basket security {}",
+                acc_id.0, sec_id.0
+            );
+            return Err(MarketError::AccDoesNotExist(acc_id));
+        };
+        let held = account.shares(sec_id);
+        if held < shares {
+            error!(
+                "Account {} holding {} shares of basket security {} attempted to redeem {}",
+                acc_id.0, held, sec_id.0, quantity
+            );
+            return Err(MarketError::InsufficientShares {
+                acc: acc_id,
+                sec: sec_id,
+                held,
+                quantity,
+            });
+        }
+        // The account takes the components in, so it must be able to hold them
+        for (component, required) in required.iter().copied() {
+            if account.shares(component).checked_add(required).is_none() {
+                return Err(overflow());
+            }
+        }
+        account.holdings.insert(sec_id, held - shares);
+        drop(account);
+
+        let delivered = match self.accounts.get_mut(&issuer) {
+            Some(mut custodian) => {
+                let short = required
+                    .iter()
+                    .map(|(component, required)| {
+                        (*component, custodian.shares(*component), *required)
+                    })
+                    .find(|(_, held, required)| held < required);
+                match short {
+                    Some((component, held, required)) => Err(MarketError::InsufficientShares {
+                        acc: issuer,
+                        sec: component,
+                        held,
+                        quantity: required as usize,
+                    }),
+                    None => {
+                        for (component, required) in required.iter().copied() {
+                            *custodian.holdings.entry(component).or_default() -= required;
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            None => Err(MarketError::AccDoesNotExist(issuer)),
+        };
+        let mut account = self.accounts.get_mut(&acc_id).unwrap();
+        if let Err(e) = delivered {
+            error!(
+                "Issuer of basket security {} could not deliver components: {}",
+                sec_id.0, e
+            );
+            let held = account.holdings.entry(sec_id).or_default();
+            *held = held.saturating_add(shares);
+            return Err(e);
+        }
+        for (component, required) in required.iter().copied() {
+            let held = account.holdings.entry(component).or_default();
+            *held = held.saturating_add(required);
+        }
+        drop(account);
+
+        if let Some(mut sec) = self.securities.get_mut(&sec_id) {
+            sec.shares_outstanding = sec.shares_outstanding.saturating_sub(quantity);
+        }
+        self.rebase_indices();
+        info!(
+            "Account {} redeemed {} shares of basket security {}",
+            acc_id.0, quantity, sec_id.0
+        );
+        Ok(held - shares)
+    }
+
+    pub fn list_securities(&self) -> Vec<SecId> {
+        let map = Arc::as_ref(&self.securities);
+        map.iter()
+            .filter(|s| s.delisted.is_none())
+            .map(|s| s.pair().0.clone())
+            .collect::<Vec<_>>()
+    }
+
+    pub fn create_security(
+        &self,
+        founding_shares: usize,
+        founding_price: Money,
+        config: SecurityConfig,
+    ) -> Result<(SecId, AccId), MarketError> {
+        if !config.tick_size.is_positive() {
+            error!(
+                "Attempted to create security with tick size {}",
+                config.tick_size
+            );
+            return Err(MarketError::InvalidListing(format!(
+                "Tick size {} is not a positive amount",
+                config.tick_size
+            )));
+        }
+        if config.lot_size == 0 {
+            error!("Attempted to create security with a lot size of zero");
+            return Err(MarketError::InvalidListing(
+                "Lot size must be at least one share".to_string(),
+            ));
+        }
+        if let Err(reason) = config.breaker.check() {
+            error!("Attempted to create security: {}", reason);
+            return Err(MarketError::InvalidListing(reason));
+        }
+
+        if let Some(basket) = &config.basket {
+            self.check_basket(basket, founding_shares, config.lot_size)?;
+        }
+        if let Some(option) = &config.option {
+            self.check_option(option, founding_shares, &config)?;
+        }
+        if let Some(future) = &config.future {
+            self.check_future(future, founding_shares, &config)?;
+        }
+
+        let sec_id = SecId(Uuid::new_v4());
+        let mut security = Security {
+            currency: config.currency,
+            tick_size: config.tick_size,
+            lot_size: config.lot_size,
+            self_trade_prevention: config.self_trade_prevention,
+            short_selling: config.short_selling,
+            fees: config.fees,
+            basket: config.basket,
+            option: config.option,
+            settles: config
+                .future
+                .as_ref()
+                .map(|_| self.next_session_close(SystemTime::now())),
+            future: config.future,
+            settlement: founding_price,
+            last_trade: founding_price,
+            shares_outstanding: founding_shares,
+            stats: SessionStats::new(founding_price, self.next_session_close(SystemTime::now())),
+            breaker: CircuitBreaker::new(config.breaker),
+            ..Default::default()
+        };
+        let founding_ticks = security.price_to_ticks(sec_id, founding_price)?;
+        // The founding shares are offered like any other ask
+        let Ok(founding_holding) = i64::try_from(founding_shares) else {
+            error!(
+                "Attempted to create security with {} founding shares",
+                founding_shares
+            );
+            return Err(MarketError::QuantityOverflow {
+                sec: sec_id,
+                quantity: founding_shares,
+            });
+        };
+        if founding_shares > 0 {
+            security.check_quantity(sec_id, founding_shares)?;
+            security.check_value(sec_id, founding_price, founding_shares)?;
+        }
+        let acc_id = self.create_account(None, 0, security.currency.clone())?;
+        security.issuer = acc_id;
+        self.securities.insert(sec_id, security);
+        self.accounts
+            .get_mut(&acc_id)
+            .unwrap()
+            .holdings
+            .insert(sec_id, founding_holding);
+        let mut security = self.securities.get_mut(&sec_id).unwrap();
+        if founding_shares > 0 {
+            let seq = self.next_seq();
+            security
+                .asks
+                .push(Ask::new(acc_id, founding_ticks, founding_shares, seq));
+        }
+        info!("Security {} created", sec_id.0);
+        drop(security);
+        self.recalculate_indices();
+
+        Ok((sec_id, acc_id))
+    }
+
+    /// Matches the books of every security, returning whether anything traded
+    pub fn run_market_loop(market: Market) -> bool {
+        let securities = market.securities;
+        let accounts = market.accounts;
+        let executions = market.executions;
+        let sequence = market.sequence;
+        let fee_account = market.fee_account;
+        let candles = market.candles;
+        let session_close = market.session_close;
+        let indices = market.indices;
+        let index_values = market.index_values;
+        let fx_rates = market.fx_rates;
+        let mut traded = false;
+        // Shares retired by buybacks filled in this pass, in each security
+        let mut retired: HashMap<SecId, usize> = HashMap::new();
+        for mut sec in securities.iter_mut() {
+            let (sec_id, sec) = sec.pair_mut();
+            trace!("Processing security {}", sec_id.0);
+            let now = Instant::now();
+            if sec.delisted.is_some() {
+                continue;
+            }
+            if sec.breaker.resume_if_due(now) {
+                info!("Trading resumed for security {}", sec_id.0);
+            }
+            if sec.breaker.is_halted(now) {
+                debug!("Security {} is halted; skipping", sec_id.0);
+                continue;
+            }
+            let clock = SystemTime::now();
+            'find: while let (Some(bid), Some(ask)) = (sec.bids.peek(), sec.asks.peek()) {
+                // The sweeper only runs now and then, so orders past their time in force
+                // may still be on the book
+                if bid.is_expired(clock) {
+                    let bid = sec.bids.pop().unwrap();
+                    sec.expire_bid(*sec_id, bid, &executions);
+                    continue;
+                }
+                if ask.is_expired(clock) {
+                    let ask = sec.asks.pop().unwrap();
+                    sec.expire_ask(*sec_id, ask, &executions);
+                    continue;
+                }
+                trace!(
+                    "Cheching a bid by account {} of {} against an ask by account {} of {}",
+                    bid.account.0,
+                    bid.price.0,
+                    ask.account.0,
+                    ask.price
+                );
+
+                if bid.price.0 >= ask.price {
+                    trace!("Processing possible transaction");
+                    let mut bid = sec.bids.pop().unwrap();
+                    let mut ask = sec.asks.pop().unwrap();
+
+                    if bid.account == ask.account {
+                        sec.prevent_self_trade(*sec_id, bid, ask, &sequence, &executions);
+                        continue;
+                    }
+
+                    // The order that was waiting first is the maker and sets the price, so
+                    // orders placed at the edge of the band, like triggered stops, trade at
+                    // the book
+                    let bid_is_maker = bid.seq < ask.seq;
+                    let price = if bid_is_maker {
+                        sec.ticks_to_price(bid.price.0)
+                    } else {
+                        sec.ticks_to_price(ask.price)
+                    };
+                    trace!("Agreed price: {}", price);
+
+                    if !sec.breaker.check_trade(now, sec.last_trade, price) {
+                        warn!(
+                            "Trade of security {} at {} would move the price too far from {}; halting trading for {:?}",
+                            sec_id.0, price, sec.last_trade, sec.breaker.config.cooloff
+                        );
+                        sec.bids.push(bid);
+                        sec.asks.push(ask);
+                        break 'find;
+                    }
+
+                    sec.record_dividends(*sec_id, &accounts, clock);
+                    let (buyer_tier, buyer_cash) = {
+                        let buyer = accounts.get(&bid.account).unwrap();
+                        (buyer.fee_tier, buyer.cash(&sec.currency))
+                    };
+                    let (quantity, cost, fees, buyer_fee, seller_fee) = {
+                        let mut seller = accounts.get_mut(&ask.account).unwrap();
+                        let (seller_id, seller) = seller.pair_mut();
+
+                        let held = seller.shares(*sec_id);
+                        let available = if sec.is_derivative() {
+                            // Selling contracts short writes them, which needs no borrow, the
+                            // contracts being covered as the order is placed
+                            i64::MAX
+                        } else {
+                            held.saturating_add(sec.short_capacity(price))
+                        };
+                        if available < 1 {
+                            warn!("Seller account {} has no shares of security {} available to sell; canceling ask {}", seller_id.0, sec_id.0, ask.id.0);
+                            let remaining = ask.remaining();
+                            ask.set_remaining(0);
+                            let _ = executions.send(Execution::of_ask(
+                                *sec_id,
+                                &ask,
+                                ExecKind::Rejected,
+                                price,
+                                remaining,
+                            ));
+                            sec.bids.push(bid);
+                            continue;
+                        }
+
+                        let quantity = bid.quantity.min(ask.quantity).min(available as usize);
+                        let (Some(notional), Some(value)) = (
+                            sec.notional(price, quantity),
+                            sec.trade_value(price, quantity),
+                        ) else {
+                            sec.cancel_overflowing(*sec_id, bid, ask, price, quantity, &executions);
+                            continue;
+                        };
+                        let seller_fee = sec
+                            .fee_schedule(seller.fee_tier)
+                            .fee(!bid_is_maker, notional);
+                        let buyer_fee = sec.fee_schedule(buyer_tier).fee(bid_is_maker, notional);
+                        // The buyer's cost and the fees are checked before either side is
+                        // touched, so a trade that can not be settled leaves both as they were
+                        let (Some(cost), Some(proceeds), Some(fees)) = (
+                            value
+                                .checked_add(buyer_fee)
+                                .filter(|cost| buyer_cash.checked_sub(*cost).is_some()),
+                            value.checked_sub(seller_fee),
+                            buyer_fee.checked_add(seller_fee),
+                        ) else {
+                            sec.cancel_overflowing(*sec_id, bid, ask, price, quantity, &executions);
+                            continue;
+                        };
+                        trace!("Seller account {} has {} shares of security {}, transaction of {} will go ahead", seller_id.0, held, sec_id.0, quantity);
+
+                        sec.accrue_borrow_fee(*sec_id, seller, now);
+                        *seller.cash_mut(&sec.currency) += proceeds;
+                        seller.adjust_shares(*sec_id, -(quantity as i64), now);
+                        if seller.shares(*sec_id) < 0 {
+                            sec.borrowers.insert(*seller_id);
+                        }
+                        if held < quantity as i64 {
+                            info!(
+                                "Seller account {} sold {} shares of security {} short",
+                                seller_id.0,
+                                quantity as i64 - held.max(0),
+                                sec_id.0
+                            );
+                        }
+                        trace!(
+                            "Removed {} shares of security {} from seller account {}",
+                            quantity,
+                            sec_id.0,
+                            seller_id.0
+                        );
+                        (quantity, cost, fees, buyer_fee, seller_fee)
+                    };
+
+                    {
+                        let mut buyer = accounts.get_mut(&bid.account).unwrap();
+                        let (buyer_id, buyer) = buyer.pair_mut();
+                        sec.accrue_borrow_fee(*sec_id, buyer, now);
+                        let cash = buyer.cash_mut(&sec.currency);
+                        *cash = cash.saturating_sub(cost);
+                        if sec.buybacks.contains(&bid.id) {
+                            sec.shares_outstanding =
+                                sec.shares_outstanding.saturating_sub(quantity);
+                            *retired.entry(*sec_id).or_default() += quantity;
+                            info!(
+                                "Issuer account {} bought back and retired {} shares of security {}",
+                                buyer_id.0, quantity, sec_id.0
+                            );
+                        } else {
+                            if let Some(borrow) = buyer.adjust_shares(*sec_id, quantity as i64, now)
+                            {
+                                sec.borrowers.remove(buyer_id);
+                                info!(
+                                    "Buyer account {} covered its short position in security {} held for {:?}, paying {} in borrow fees",
+                                    buyer_id.0,
+                                    sec_id.0,
+                                    now.duration_since(borrow.opened),
+                                    borrow.fees
+                                );
+                            }
+                            trace!(
+                                "Added {} shares of security {} to buyer account {}",
+                                quantity,
+                                sec_id.0,
+                                buyer_id.0
+                            );
+                        }
+                    }
+
+                    if let Some(mut exchange) = accounts.get_mut(&fee_account) {
+                        let cash = exchange.cash_mut(&sec.currency);
+                        *cash = cash.saturating_add(fees);
+                    }
+                    trace!(
+                        "Charged buyer {} and seller {} in fees",
+                        buyer_fee,
+                        seller_fee
+                    );
+
+                    info!(
+                        "Transaction occured between buyer {} and seller {}:",
+                        bid.account.0, ask.account.0
+                    );
+                    sec.last_trade = price;
+                    sec.breaker.record_trade(now, price);
+
+                    info!(
+                        "{} shares of security {} sold for {}",
+                        quantity, sec_id.0, price
+                    );
+
+                    bid.quantity -= quantity;
+                    ask.quantity -= quantity;
+                    let time = SystemTime::now();
+                    for candle in sec.candles.record(time, price, quantity) {
+                        let _ = candles.send((*sec_id, candle));
+                    }
+                    sec.stats
+                        .roll_if_due(time, session_close_after(session_close, time));
+                    sec.stats.record(price, quantity);
+                    traded = true;
+                    if sec.trades.len() == MAX_TRADES {
+                        sec.trades.pop_front();
+                    }
+                    sec.trades.push_back(Trade {
+                        time,
+                        price,
+                        quantity,
+                        buyer: bid.account,
+                        seller: ask.account,
+                        bid: bid.id,
+                        ask: ask.id,
+                        taker: if bid_is_maker { Side::Sell } else { Side::Buy },
+                        buyer_fee,
+                        seller_fee,
+                    });
+                    let _ = executions.send(Execution {
+                        fee: buyer_fee,
+                        ..Execution::of_bid(*sec_id, &bid, ExecKind::Fill, price, quantity)
+                    });
+                    let _ = executions.send(Execution {
+                        fee: seller_fee,
+                        ..Execution::of_ask(*sec_id, &ask, ExecKind::Fill, price, quantity)
+                    });
+                    if bid.remaining() == 0 {
+                        sec.buybacks.remove(&bid.id);
+                    }
+                    sec.replenish_bid(*sec_id, bid, &sequence, &executions);
+                    sec.replenish_ask(*sec_id, ask, &sequence, &executions);
+
+                    sec.trigger_stops(*sec_id, &sequence, &executions);
+                } else {
+                    debug!("No available transactions");
+                    break 'find;
+                }
+            }
+        }
+
+        if traded {
+            let rates = fx_rates.read().unwrap().clone();
+            let mut traded_at = quotes(&securities, &rates);
+            // Indices move with the prices traded at before the shares bought back are
+            // taken out, which moves none of them
+            for (sec_id, retired) in retired.iter() {
+                if let Some(quote) = traded_at.get_mut(sec_id) {
+                    quote.shares_outstanding += retired;
+                }
+            }
+            update_indices(&traded_at, &indices, &index_values);
+            if !retired.is_empty() {
+                let quotes = quotes(&securities, &rates);
+                for mut index in indices.iter_mut() {
+                    index.rebase(&quotes);
+                }
+            }
+        }
+        traded
+    }
+}
+
+/// Recalculates every index from the quotes of the listed securities, sending the
+/// values that changed
+fn update_indices(
+    quotes: &HashMap<SecId, Quote>,
+    indices: &DashMap<String, Index>,
+    index_values: &broadcast::Sender<(String, f64)>,
+) {
+    for mut index in indices.iter_mut() {
+        let (name, index) = index.pair_mut();
+        if index.update(quotes) {
+            trace!("Index {} moved to {}", name, index.value);
+            let _ = index_values.send((name.clone(), index.value));
+        }
+    }
+}
+
+/// Prices of the listed securities in the base currency, leaving out those whose
+/// currency has no rate
+fn quotes(securities: &DashMap<SecId, Security>, rates: &FxRates) -> HashMap<SecId, Quote> {
+    let base = Currency::default();
+    securities
+        .iter()
+        .filter(|sec| sec.delisted.is_none() && !sec.is_derivative())
+        .filter_map(|sec| {
+            let price = rates.convert(sec.last_trade, &sec.currency, &base)?;
+            Some((
+                *sec.key(),
+                Quote {
+                    price,
+                    shares_outstanding: sec.shares_outstanding,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// The first time of day `session_close`, in UTC, comes after `now`
+fn session_close_after(session_close: Duration, now: SystemTime) -> SystemTime {
+    const DAY: u64 = 24 * 60 * 60;
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let midnight = Duration::from_secs(since_epoch.as_secs() / DAY * DAY);
+    let mut close = UNIX_EPOCH + midnight + session_close;
+    if close <= now {
+        close += Duration::from_secs(DAY);
+    }
+    close
+}
+
+#[derive(Error, Debug)]
+pub enum MarketError {
+    #[error("Security {} does not exist", 0.0)]
+    SecDoesNotExist(SecId),
+    #[error("Account {} does not exist", 0.0)]
+    AccDoesNotExist(AccId),
+    #[error("No bids are placed for security {}", 0.0)]
+    NoBids(SecId),
+    #[error("No asks are placed for security {}", 0.0)]
+    NoAsks(SecId),
+    #[error("Trading of security {} is halted", .0 .0)]
+    Halted(SecId),
+    #[error("Price {price} is outside the band {low}..={high} of security {}", .sec.0)]
+    OutsidePriceBand {
+        sec: SecId,
+        price: Money,
+        low: Money,
+        high: Money,
+    },
+    #[error("Price {price} is not valid for security {}", .sec.0)]
+    InvalidPrice { sec: SecId, price: Money },
+    #[error("Price {price} is not a multiple of the tick size {tick_size} of security {}", .sec.0)]
+    OffTick {
+        sec: SecId,
+        price: Money,
+        tick_size: Money,
+    },
+    #[error("Quantity {quantity} is not a multiple of the lot size {lot_size} of security {}", .sec.0)]
+    OddLot {
+        sec: SecId,
+        quantity: usize,
+        lot_size: usize,
+    },
+    #[error("Invalid listing: {0}")]
+    InvalidListing(String),
+    #[error("Order {} does not exist", .0 .0)]
+    OrderDoesNotExist(OrderId),
+    #[error("Order {} is not owned by account {}", .order.0, .acc.0)]
+    NotOrderOwner { order: OrderId, acc: AccId },
+    #[error("Expiry time {0:?} has already passed")]
+    ExpiryInPast(SystemTime),
+    #[error("Invalid margin {0:?}")]
+    InvalidMargin(Margin),
+    #[error("Account {} has equity {equity} but needs {required} of margin", .acc.0)]
+    InsufficientMargin {
+        acc: AccId,
+        equity: Money,
+        required: Money,
+    },
+    #[error("Account {} has {cash} in cash but needs {required} to cover its bids", .acc.0)]
+    InsufficientCash {
+        acc: AccId,
+        cash: Money,
+        required: Money,
+    },
+    #[error("Order for {quantity} shares is over the limit of {max}")]
+    OrderTooLarge { quantity: usize, max: usize },
+    #[error("Order worth {notional} is over the limit of {max}")]
+    NotionalTooLarge { notional: Money, max: Money },
+    #[error("Account {} already has the limit of {max} open orders", .acc.0)]
+    TooManyOpenOrders { acc: AccId, max: usize },
+    #[error("Order would leave account {} with a position of {position} in security {}, over the limit of {max}", .acc.0, .sec.0)]
+    PositionLimit {
+        acc: AccId,
+        sec: SecId,
+        position: i64,
+        max: usize,
+    },
+    #[error("Account {} placed more than {max} orders in the last second", .acc.0)]
+    OrderRateExceeded { acc: AccId, max: usize },
+    #[error("Index {0} does not exist")]
+    IndexDoesNotExist(String),
+    #[error("Cannot split {from} shares into {to}")]
+    InvalidSplit { to: usize, from: usize },
+    #[error("Invalid dividend of {per_share} per share of security {}, recorded at {record:?} and paid at {pay:?}", .sec.0)]
+    InvalidDividend {
+        sec: SecId,
+        per_share: Money,
+        record: SystemTime,
+        pay: SystemTime,
+    },
+    #[error("Security {} is delisted", .0 .0)]
+    Delisted(SecId),
+    #[error("Security {} is not a basket", .0 .0)]
+    NotABasket(SecId),
+    #[error("Series {} has expired", .0 .0)]
+    SeriesExpired(SecId),
+    #[error("Invalid exchange rate {rate} for {currency}")]
+    InvalidFxRate { currency: Currency, rate: f64 },
+    #[error("No exchange rate to convert {from} to {to}")]
+    NoFxRate { from: Currency, to: Currency },
+    #[error("Invalid amount {0}")]
+    InvalidAmount(Money),
+    #[error("Account {} holds {held} shares of security {}, fewer than {quantity}", .acc.0, .sec.0)]
+    InsufficientShares {
+        acc: AccId,
+        sec: SecId,
+        held: i64,
+        quantity: usize,
+    },
+    #[error("Stop at {trigger} for security {} has been reached by the last trade of {last_trade}", .sec.0)]
+    StopReached {
+        sec: SecId,
+        trigger: Money,
+        last_trade: Money,
+    },
+    #[error("Value of {quantity} shares of security {} at {price} is too large", .sec.0)]
+    ValueOverflow {
+        sec: SecId,
+        price: Money,
+        quantity: usize,
+    },
+    #[error("{quantity} shares of security {} are more than can be held", .sec.0)]
+    QuantityOverflow { sec: SecId, quantity: usize },
+    #[error("Futures series {} on security {} is listed", .future.0, .sec.0)]
+    FuturesListed { sec: SecId, future: SecId },
+}
+
+impl From<MarketError> for Status {
+    fn from(value: MarketError) -> Self {
+        match value {
+            MarketError::SecDoesNotExist(sec) => {
+                Status::failed_precondition(format!("Security {} does not exist", sec.0))
+            }
+            MarketError::AccDoesNotExist(acc) => {
+                Status::failed_precondition(format!("Account {} does not exist", acc.0))
+            }
+            MarketError::NoBids(sec) => {
+                Status::ok(format!("No bids are placed for security {}", sec.0))
+            }
+            MarketError::NoAsks(sec) => {
+                Status::ok(format!("No asks are placed for security {}", sec.0))
+            }
+            MarketError::Halted(sec) => {
+                Status::unavailable(format!("Trading of security {} is halted", sec.0))
+            }
+            MarketError::OutsidePriceBand {
+                sec,
+                price,
+                low,
+                high,
+            } => Status::out_of_range(format!(
+                "Price {} is outside the band {}..={} of security {}",
+                price, low, high, sec.0
+            )),
+            MarketError::InvalidPrice { sec, price } => Status::invalid_argument(format!(
+                "Price {} is not valid for security {}",
+                price, sec.0
+            )),
+            MarketError::OffTick {
+                sec,
+                price,
+                tick_size,
+            } => Status::invalid_argument(format!(
+                "Price {} is not a multiple of the tick size {} of security {}",
+                price, tick_size, sec.0
+            )),
+            MarketError::OddLot {
+                sec,
+                quantity,
+                lot_size,
+            } => Status::invalid_argument(format!(
+                "Quantity {} is not a multiple of the lot size {} of security {}",
+                quantity, lot_size, sec.0
+            )),
+            MarketError::InvalidListing(reason) => {
+                Status::invalid_argument(format!("Invalid listing: {}", reason))
+            }
+            MarketError::StopReached {
+                sec,
+                trigger,
+                last_trade,
+            } => Status::failed_precondition(format!(
+                "Stop at {} for security {} has been reached by the last trade of {}",
+                trigger, sec.0, last_trade
+            )),
+            MarketError::ValueOverflow {
+                sec,
+                price,
+                quantity,
+            } => Status::out_of_range(format!(
+                "Value of {} shares of security {} at {} is too large",
+                quantity, sec.0, price
+            )),
+            MarketError::QuantityOverflow { sec, quantity } => Status::out_of_range(format!(
+                "{} shares of security {} are more than can be held",
+                quantity, sec.0
+            )),
+            MarketError::FuturesListed { sec, future } => Status::failed_precondition(format!(
+                "Futures series {} on security {} is listed",
+                future.0, sec.0
+            )),
+            MarketError::OrderDoesNotExist(order) => {
+                Status::not_found(format!("Order {} does not exist", order.0))
+            }
+            MarketError::NotOrderOwner { order, acc } => Status::permission_denied(format!(
+                "Order {} is not owned by account {}",
+                order.0, acc.0
+            )),
+            MarketError::ExpiryInPast(expiry) => {
+                Status::invalid_argument(format!("Expiry time {:?} has already passed", expiry))
+            }
+            MarketError::InvalidMargin(margin) => Status::invalid_argument(format!(
+                "Maintenance margin {} must be above zero and at most initial margin {}, which must be at most one",
+                margin.maintenance, margin.initial
+            )),
+            MarketError::InsufficientMargin {
+                acc,
+                equity,
+                required,
+            } => Status::failed_precondition(format!(
+                "Account {} has equity {} but needs {} of margin",
+                acc.0, equity, required
+            )),
+            MarketError::InsufficientCash {
+                acc,
+                cash,
+                required,
+            } => Status::failed_precondition(format!(
+                "Account {} has {} in cash but needs {} to cover its bids",
+                acc.0, cash, required
+            )),
+            MarketError::OrderTooLarge { quantity, max } => Status::invalid_argument(format!(
+                "Order for {} shares is over the limit of {}",
+                quantity, max
+            )),
+            MarketError::NotionalTooLarge { notional, max } => Status::invalid_argument(format!(
+                "Order worth {} is over the limit of {}",
+                notional, max
+            )),
+            MarketError::TooManyOpenOrders { acc, max } => Status::failed_precondition(format!(
+                "Account {} already has the limit of {} open orders",
+                acc.0, max
+            )),
+            MarketError::PositionLimit {
+                acc,
+                sec,
+                position,
+                max,
+            } => Status::failed_precondition(format!(
+                "Order would leave account {} with a position of {} in security {}, over the limit of {}",
+                acc.0, position, sec.0, max
+            )),
+            MarketError::OrderRateExceeded { acc, max } => Status::resource_exhausted(format!(
+                "Account {} placed more than {} orders in the last second",
+                acc.0, max
+            )),
+            MarketError::IndexDoesNotExist(name) => {
+                Status::not_found(format!("Index {} does not exist", name))
+            }
+            MarketError::InvalidSplit { to, from } => {
+                Status::invalid_argument(format!("Cannot split {} shares into {}", from, to))
+            }
+            MarketError::InvalidDividend {
+                sec,
+                per_share,
+                record,
+                pay,
+            } => Status::invalid_argument(format!(
+                "Invalid dividend of {} per share of security {}, recorded at {:?} and paid at {:?}",
+                per_share, sec.0, record, pay
+            )),
+            MarketError::Delisted(sec) => {
+                Status::failed_precondition(format!("Security {} is delisted", sec.0))
+            }
+            MarketError::NotABasket(sec) => {
+                Status::failed_precondition(format!("Security {} is not a basket", sec.0))
+            }
+            MarketError::SeriesExpired(sec) => {
+                Status::failed_precondition(format!("Series {} has expired", sec.0))
+            }
+            MarketError::InvalidFxRate { currency, rate } => Status::invalid_argument(format!(
+                "Invalid exchange rate {} for {}",
+                rate, currency
+            )),
+            MarketError::NoFxRate { from, to } => Status::failed_precondition(format!(
+                "No exchange rate to convert {} to {}",
+                from, to
+            )),
+            MarketError::InvalidAmount(amount) => {
+                Status::invalid_argument(format!("Invalid amount {}", amount))
+            }
+            MarketError::InsufficientShares {
+                acc,
+                sec,
+                held,
+                quantity,
+            } => Status::failed_precondition(format!(
+                "Account {} holds {} shares of security {}, fewer than {}",
+                acc.0, held, sec.0, quantity
+            )),
+        }
+    }
+}
+
+/// Shown shares at one price of the order book
+#[derive(Debug, Clone, Copy)]
+pub struct DepthLevel {
+    pub price: Money,
+    pub quantity: usize,
+    pub orders: usize,
+}
+
+/// What to do when a bid and an ask of the same account would trade with each other
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTradePrevention {
+    #[default]
+    CancelNewest,
+    CancelOldest,
+    CancelBoth,
+    /// Reduce both orders by the smaller quantity
+    Decrement,
+}
+
+/// Limits on selling shares of a security an account does not hold
+#[derive(Debug, Clone, Copy)]
+pub struct ShortSelling {
+    /// Largest value a short position may reach before it is bought in
+    pub max_value: Money,
+    /// Fee charged on the value of borrowed shares, as a fraction a year
+    pub borrow_rate: f64,
+}
+
+/// Trading rules a security is listed with
+#[derive(Debug, Clone)]
+pub struct SecurityConfig {
+    /// Currency the security is priced and settled in
+    pub currency: Currency,
+    pub tick_size: Money,
+    /// Orders must be for a whole number of lots
+    pub lot_size: usize,
+    pub breaker: BreakerConfig,
+    pub self_trade_prevention: SelfTradePrevention,
+    /// Short selling is not allowed when unset
+    pub short_selling: Option<ShortSelling>,
+    /// Fees charged to accounts of each fee tier, those of tiers past the end are
+    /// charged the last. Trading is free when empty.
+    pub fees: Vec<FeeSchedule>,
+    /// Makes the security a basket of others, its shares created and redeemed for
+    /// them
+    pub basket: Option<Basket>,
+    /// Makes the security a series of option contracts, written by selling them
+    pub option: Option<OptionContract>,
+    /// Makes the security a series of cash-settled futures, the listing price being
+    /// the first settlement price
+    pub future: Option<FutureContract>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            currency: Currency::default(),
+            tick_size: Money(100),
+            lot_size: 1,
+            breaker: Default::default(),
+            self_trade_prevention: Default::default(),
+            short_selling: None,
+            fees: Vec::new(),
+            basket: None,
+            option: None,
+            future: None,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Security {
-    last_trade: f64,
+    /// Currency prices, fees and cash paid on the security are in
+    currency: Currency,
+    last_trade: Money,
+    /// Shares held by all accounts together, short positions netting out the shares
+    /// they sold
+    shares_outstanding: usize,
+    tick_size: Money,
+    lot_size: usize,
     bids: BinaryHeap<Bid>,
     asks: BinaryHeap<Ask>,
+    breaker: CircuitBreaker,
+    self_trade_prevention: SelfTradePrevention,
+    /// Stop orders waiting for their trigger price, kept off the book
+    stops: Vec<StopOrder>,
+    short_selling: Option<ShortSelling>,
+    /// Accounts that may be short the security, the only ones borrows are settled for
+    borrowers: HashSet<AccId>,
+    fees: Vec<FeeSchedule>,
+    /// Most recent trades, oldest first
+    trades: VecDeque<Trade>,
+    candles: CandleSeries,
+    stats: SessionStats,
+    /// Dividends declared and not yet paid
+    dividends: Vec<Dividend>,
+    /// Account the founding shares were created in, which issues and buys back shares
+    issuer: AccId,
+    /// Bids of the issuer whose shares are retired as they are bought
+    buybacks: HashSet<OrderId>,
+    /// When trading ended for good, the history is kept
+    delisted: Option<SystemTime>,
+    /// Securities backing the shares, held by the issuer
+    basket: Option<Basket>,
+    /// Terms of the contracts when the security is an option series
+    option: Option<OptionContract>,
+    /// Terms of the contracts when the security is a futures series
+    future: Option<FutureContract>,
+    /// Price futures positions were last marked to
+    settlement: Money,
+    /// When futures positions are next marked to the last trade
+    settles: Option<SystemTime>,
+}
+
+impl Security {
+    fn price_to_ticks(&self, sec_id: SecId, price: Money) -> Result<Ticks, MarketError> {
+        if !price.is_positive() {
+            error!("Rejected invalid price {} for security {}", price, sec_id.0);
+            return Err(MarketError::InvalidPrice { sec: sec_id, price });
+        }
+        if price.minor_units() % self.tick_size.minor_units() != 0 {
+            error!(
+                "Rejected price {} for security {} off its tick size {}",
+                price, sec_id.0, self.tick_size
+            );
+            return Err(MarketError::OffTick {
+                sec: sec_id,
+                price,
+                tick_size: self.tick_size,
+            });
+        }
+        Ok(price.minor_units() / self.tick_size.minor_units())
+    }
+
+    fn ticks_to_price(&self, ticks: Ticks) -> Money {
+        self.tick_size * ticks
+    }
+
+    fn check_quantity(&self, sec_id: SecId, quantity: usize) -> Result<(), MarketError> {
+        if quantity == 0 || !quantity.is_multiple_of(self.lot_size) {
+            error!(
+                "Rejected quantity {} for security {} with lot size {}",
+                quantity, sec_id.0, self.lot_size
+            );
+            return Err(MarketError::OddLot {
+                sec: sec_id,
+                quantity,
+                lot_size: self.lot_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Rejects stops the last trade has already reached, or whose limit is outside
+    /// the price band
+    fn check_stop(
+        &self,
+        sec_id: SecId,
+        side: Side,
+        trigger: Ticks,
+        limit: Option<Money>,
+    ) -> Result<(), MarketError> {
+        if self.stop_reached(side, trigger) {
+            let trigger = self.ticks_to_price(trigger);
+            error!(
+                "Rejected {:?} stop for security {} triggered at {} as the last trade of {} has reached it",
+                side, sec_id.0, trigger, self.last_trade
+            );
+            return Err(MarketError::StopReached {
+                sec: sec_id,
+                trigger,
+                last_trade: self.last_trade,
+            });
+        }
+        if let Some(limit) = limit {
+            self.check_order_price(sec_id, limit)?;
+        }
+        Ok(())
+    }
+
+    /// Cash the bids and buy stops of an account would cost if filled at their limits
+    fn committed_cash(&self, acc_id: AccId, fee_tier: usize) -> Money {
+        let bids = self
+            .bids
+            .iter()
+            .filter(|b| b.account == acc_id)
+            .map(|b| (b.price.0, b.remaining()));
+        let stops = self
+            .stops
+            .iter()
+            .filter(|s| s.account == acc_id && s.side == Side::Buy)
+            .map(|s| (s.limit.unwrap_or(s.trigger), s.quantity));
+        bids.chain(stops)
+            .map(|(ticks, quantity)| self.bid_cost(self.ticks_to_price(ticks), quantity, fee_tier))
+            .try_fold(Money::ZERO, |total, cost| total.checked_add(cost?))
+            .unwrap_or(Money::MAX)
+    }
+
+    /// Whether the last trade has reached the trigger of a stop on `side`
+    fn stop_reached(&self, side: Side, trigger: Ticks) -> bool {
+        let trigger = self.ticks_to_price(trigger);
+        match side {
+            Side::Buy => self.last_trade >= trigger,
+            Side::Sell => self.last_trade <= trigger,
+        }
+    }
+
+    /// Rejects orders whose value at `price` could not be held, so trading them
+    /// can not overflow. Returns the value.
+    fn check_value(
+        &self,
+        sec_id: SecId,
+        price: Money,
+        quantity: usize,
+    ) -> Result<Money, MarketError> {
+        self.notional(price, quantity).ok_or_else(|| {
+            error!(
+                "Rejected order for {} shares of security {} at {} as its value is too large",
+                quantity, sec_id.0, price
+            );
+            MarketError::ValueOverflow {
+                sec: sec_id,
+                price,
+                quantity,
+            }
+        })
+    }
+
+    /// Most a bid for `quantity` shares at `price` could cost an account in
+    /// `fee_tier`, fees included. None if it can not be held.
+    fn bid_cost(&self, price: Money, quantity: usize, fee_tier: usize) -> Option<Money> {
+        let notional = self.notional(price, quantity)?;
+        notional.checked_add(self.fee_schedule(fee_tier).max_fee(notional))
+    }
+
+    fn fee_schedule(&self, fee_tier: usize) -> FeeSchedule {
+        self.fees
+            .get(fee_tier)
+            .or(self.fees.last())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Whether the security is a series of contracts, whose short positions are
+    /// opened by writing them rather than borrowing
+    fn is_derivative(&self) -> bool {
+        self.option.is_some() || self.future.is_some()
+    }
+
+    /// Value of `quantity` shares or contracts at `price`, fees being charged on it.
+    /// None if it can not be held.
+    fn notional(&self, price: Money, quantity: usize) -> Option<Money> {
+        let multiplier = self.future.as_ref().map_or(1, |future| future.multiplier);
+        let units = i64::try_from(quantity.checked_mul(multiplier)?).ok()?;
+        price.checked_mul(units)
+    }
+
+    /// Cash paid by the buyer of `quantity` shares at `price` to the seller. Futures
+    /// positions are taken on at the last settlement price, the difference from the
+    /// trade price paid straight away. None if it can not be held.
+    fn trade_value(&self, price: Money, quantity: usize) -> Option<Money> {
+        let quantity = i64::try_from(quantity).ok()?;
+        match &self.future {
+            Some(future) => future.variation(quantity, self.settlement, price),
+            None => price.checked_mul(quantity),
+        }
+    }
+
+    /// Shares an account may sell beyond those it holds when selling at `price`
+    fn short_capacity(&self, price: Money) -> i64 {
+        match self.short_selling {
+            Some(short_selling) if price.is_positive() => {
+                short_selling.max_value.minor_units() / price.minor_units()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Records who holds the security for dividends whose record time has passed.
+    /// Done before every trade as well as by the sweeper, so trades after the record
+    /// time are never counted, though other changes to holdings until the next sweep
+    /// are.
+    fn record_dividends(
+        &mut self,
+        sec_id: SecId,
+        accounts: &DashMap<AccId, Account>,
+        now: SystemTime,
+    ) {
+        for dividend in self.dividends.iter_mut() {
+            if dividend.holders.is_some() || now < dividend.record {
+                continue;
+            }
+            let holders: Vec<_> = accounts
+                .iter()
+                .map(|account| (*account.key(), account.shares(sec_id)))
+                .filter(|(acc_id, shares)| *shares != 0 && *acc_id != self.issuer)
+                .collect();
+            info!(
+                "Recorded {} holders of security {} for dividend of {} per share",
+                holders.len(),
+                sec_id.0,
+                dividend.per_share
+            );
+            dividend.holders = Some(holders);
+        }
+    }
+
+    /// Charges the account for borrowing shares since it was last charged, valued at
+    /// the last trade
+    fn accrue_borrow_fee(&self, sec_id: SecId, account: &mut Account, now: Instant) -> Money {
+        match self.short_selling {
+            Some(short_selling) => {
+                let value = self.last_trade * (-account.shares(sec_id)).max(0);
+                account.accrue_borrow_fee(
+                    sec_id,
+                    value,
+                    &self.currency,
+                    short_selling.borrow_rate,
+                    now,
+                )
+            }
+            None => Money::ZERO,
+        }
+    }
+
+    /// Lowest and highest price on the tick grid within the price band
+    fn band_ticks(&self) -> (Ticks, Ticks) {
+        let (low, high) = self.breaker.band(self.last_trade);
+        let tick = self.tick_size.minor_units();
+        let low = (low.minor_units() + tick - 1) / tick;
+        let high = high.minor_units() / tick;
+        (low.max(1), high)
+    }
+
+    /// Moves stop orders whose trigger price the last trade has reached into the book
+    fn trigger_stops(
+        &mut self,
+        sec_id: SecId,
+        sequence: &AtomicU64,
+        executions: &broadcast::Sender<Execution>,
+    ) {
+        let last_trade = self.last_trade;
+        let (mut triggered, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.stops)
+            .into_iter()
+            .partition(|stop| self.stop_reached(stop.side, stop.trigger));
+        self.stops = waiting;
+        triggered.sort_by_key(|stop| stop.seq);
+
+        let (low, high) = self.band_ticks();
+        for mut stop in triggered {
+            if let Some(limit) = stop.limit {
+                match self.check_order_price(sec_id, self.ticks_to_price(limit)) {
+                    Ok(()) => {}
+                    Err(MarketError::Halted(_)) => {
+                        self.stops.push(stop);
+                        continue;
+                    }
+                    Err(_) => {
+                        warn!(
+                            "Stop {} of account {} for security {} triggered at {} with a limit no longer valid; canceled",
+                            stop.id.0, stop.account.0, sec_id.0, last_trade
+                        );
+                        let quantity = stop.quantity;
+                        stop.quantity = 0;
+                        let _ = executions.send(Execution::of_stop(
+                            sec_id,
+                            &stop,
+                            ExecKind::Rejected,
+                            self.ticks_to_price(limit),
+                            quantity,
+                        ));
+                        continue;
+                    }
+                }
+            }
+            let seq = sequence.fetch_add(1, Ordering::Relaxed);
+            match stop.side {
+                Side::Buy => {
+                    let bid = Bid {
+                        id: stop.id,
+                        price: Reverse(stop.limit.unwrap_or(high)),
+                        quantity: stop.quantity,
+                        hidden: 0,
+                        display: None,
+                        expires_at: None,
+                        account: stop.account,
+                        seq,
+                    };
+                    let price = self.ticks_to_price(bid.price.0);
+                    info!(
+                        "Stop {} of account {} for security {} triggered at {}; placed bid for {} shares at max price of {}",
+                        stop.id.0, stop.account.0, sec_id.0, last_trade, bid.quantity, price
+                    );
+                    let _ = executions.send(Execution::of_bid(
+                        sec_id,
+                        &bid,
+                        ExecKind::StopTriggered,
+                        price,
+                        bid.quantity,
+                    ));
+                    self.bids.push(bid);
+                }
+                Side::Sell => {
+                    let ask = Ask {
+                        id: stop.id,
+                        price: stop.limit.unwrap_or(low),
+                        quantity: stop.quantity,
+                        hidden: 0,
+                        display: None,
+                        expires_at: None,
+                        account: stop.account,
+                        seq,
+                    };
+                    let price = self.ticks_to_price(ask.price);
+                    info!(
+                        "Stop {} of account {} for security {} triggered at {}; placed ask for {} shares at min price of {}",
+                        stop.id.0, stop.account.0, sec_id.0, last_trade, ask.quantity, price
+                    );
+                    let _ = executions.send(Execution::of_ask(
+                        sec_id,
+                        &ask,
+                        ExecKind::StopTriggered,
+                        price,
+                        ask.quantity,
+                    ));
+                    self.asks.push(ask);
+                }
+            }
+        }
+    }
+
+    fn expire_bid(&self, sec_id: SecId, mut bid: Bid, executions: &broadcast::Sender<Execution>) {
+        info!(
+            "Bid {} of account {} for security {} expired",
+            bid.id.0, bid.account.0, sec_id.0
+        );
+        let price = self.ticks_to_price(bid.price.0);
+        let quantity = bid.remaining();
+        bid.set_remaining(0);
+        let _ = executions.send(Execution::of_bid(
+            sec_id,
+            &bid,
+            ExecKind::Expired,
+            price,
+            quantity,
+        ));
+    }
+
+    fn expire_ask(&self, sec_id: SecId, mut ask: Ask, executions: &broadcast::Sender<Execution>) {
+        info!(
+            "Ask {} of account {} for security {} expired",
+            ask.id.0, ask.account.0, sec_id.0
+        );
+        let price = self.ticks_to_price(ask.price);
+        let quantity = ask.remaining();
+        ask.set_remaining(0);
+        let _ = executions.send(Execution::of_ask(
+            sec_id,
+            &ask,
+            ExecKind::Expired,
+            price,
+            quantity,
+        ));
+    }
+
+    /// Cancels whichever of a bid and an ask was placed last when trading `quantity`
+    /// shares between them at `price` is worth too much to hold, putting the other
+    /// back
+    fn cancel_overflowing(
+        &mut self,
+        sec_id: SecId,
+        mut bid: Bid,
+        mut ask: Ask,
+        price: Money,
+        quantity: usize,
+        executions: &broadcast::Sender<Execution>,
+    ) {
+        warn!(
+            "Trade of {} shares of security {} at {} is worth too much to hold",
+            quantity, sec_id.0, price
+        );
+        if bid.seq > ask.seq {
+            warn!(
+                "Canceled bid {} of account {} for security {} as its value is too large",
+                bid.id.0, bid.account.0, sec_id.0
+            );
+            let quantity = bid.remaining();
+            bid.set_remaining(0);
+            let _ = executions.send(Execution::of_bid(
+                sec_id,
+                &bid,
+                ExecKind::Overflowed,
+                price,
+                quantity,
+            ));
+            self.asks.push(ask);
+        } else {
+            warn!(
+                "Canceled ask {} of account {} for security {} as its value is too large",
+                ask.id.0, ask.account.0, sec_id.0
+            );
+            let quantity = ask.remaining();
+            ask.set_remaining(0);
+            let _ = executions.send(Execution::of_ask(
+                sec_id,
+                &ask,
+                ExecKind::Overflowed,
+                price,
+                quantity,
+            ));
+            self.bids.push(bid);
+        }
+    }
+
+    /// Resolves a bid and an ask of the same account meeting, putting back whatever
+    /// is left of them
+    fn prevent_self_trade(
+        &mut self,
+        sec_id: SecId,
+        mut bid: Bid,
+        mut ask: Ask,
+        sequence: &AtomicU64,
+        executions: &broadcast::Sender<Execution>,
+    ) {
+        let bid_price = self.ticks_to_price(bid.price.0);
+        let ask_price = self.ticks_to_price(ask.price);
+        let (cancel_bid, cancel_ask) = match self.self_trade_prevention {
+            SelfTradePrevention::CancelNewest => (bid.seq > ask.seq, ask.seq > bid.seq),
+            SelfTradePrevention::CancelOldest => (bid.seq < ask.seq, ask.seq < bid.seq),
+            SelfTradePrevention::CancelBoth => (true, true),
+            SelfTradePrevention::Decrement => {
+                let quantity = bid.quantity.min(ask.quantity);
+                bid.quantity -= quantity;
+                ask.quantity -= quantity;
+                warn!(
+                    "Decremented bid {} and ask {} of account {} for security {} by {} shares to prevent a self trade",
+                    bid.id.0, ask.id.0, bid.account.0, sec_id.0, quantity
+                );
+                let _ = executions.send(Execution::of_bid(
+                    sec_id,
+                    &bid,
+                    ExecKind::SelfTradeDecremented,
+                    bid_price,
+                    quantity,
+                ));
+                let _ = executions.send(Execution::of_ask(
+                    sec_id,
+                    &ask,
+                    ExecKind::SelfTradeDecremented,
+                    ask_price,
+                    quantity,
+                ));
+                (false, false)
+            }
+        };
+
+        if cancel_bid {
+            warn!(
+                "Canceled bid {} of account {} for security {} to prevent a self trade",
+                bid.id.0, bid.account.0, sec_id.0
+            );
+            let quantity = bid.remaining();
+            bid.set_remaining(0);
+            let _ = executions.send(Execution::of_bid(
+                sec_id,
+                &bid,
+                ExecKind::SelfTradeCanceled,
+                bid_price,
+                quantity,
+            ));
+        } else {
+            self.replenish_bid(sec_id, bid, sequence, executions);
+        }
+
+        if cancel_ask {
+            warn!(
+                "Canceled ask {} of account {} for security {} to prevent a self trade",
+                ask.id.0, ask.account.0, sec_id.0
+            );
+            let quantity = ask.remaining();
+            ask.set_remaining(0);
+            let _ = executions.send(Execution::of_ask(
+                sec_id,
+                &ask,
+                ExecKind::SelfTradeCanceled,
+                ask_price,
+                quantity,
+            ));
+        } else {
+            self.replenish_ask(sec_id, ask, sequence, executions);
+        }
+    }
+
+    /// Puts a bid back on the book if anything is left of it, showing the next slice
+    /// of an iceberg once the shown one is used up
+    fn replenish_bid(
+        &mut self,
+        sec_id: SecId,
+        mut bid: Bid,
+        sequence: &AtomicU64,
+        executions: &broadcast::Sender<Execution>,
+    ) {
+        if bid.quantity == 0 && bid.hidden > 0 {
+            bid.replenish(sequence.fetch_add(1, Ordering::Relaxed));
+            debug!(
+                "Replenished bid {} for security {} with {} shares, {} hidden",
+                bid.id.0, sec_id.0, bid.quantity, bid.hidden
+            );
+            let price = self.ticks_to_price(bid.price.0);
+            let _ = executions.send(Execution::of_bid(
+                sec_id,
+                &bid,
+                ExecKind::Replenished,
+                price,
+                bid.quantity,
+            ));
+        }
+        if bid.quantity > 0 {
+            self.bids.push(bid);
+        }
+    }
+
+    /// Puts an ask back on the book if anything is left of it, showing the next slice
+    /// of an iceberg once the shown one is used up
+    fn replenish_ask(
+        &mut self,
+        sec_id: SecId,
+        mut ask: Ask,
+        sequence: &AtomicU64,
+        executions: &broadcast::Sender<Execution>,
+    ) {
+        if ask.quantity == 0 && ask.hidden > 0 {
+            ask.replenish(sequence.fetch_add(1, Ordering::Relaxed));
+            debug!(
+                "Replenished ask {} for security {} with {} shares, {} hidden",
+                ask.id.0, sec_id.0, ask.quantity, ask.hidden
+            );
+            let price = self.ticks_to_price(ask.price);
+            let _ = executions.send(Execution::of_ask(
+                sec_id,
+                &ask,
+                ExecKind::Replenished,
+                price,
+                ask.quantity,
+            ));
+        }
+        if ask.quantity > 0 {
+            self.asks.push(ask);
+        }
+    }
+
+    /// Restates the book and price history as if every `from` shares had been `to`
+    /// shares all along. Order quantities are rounded down to whole lots and limit
+    /// prices to whole ticks in favour of the account that placed them.
+    fn split(
+        &mut self,
+        sec_id: SecId,
+        to: usize,
+        from: usize,
+        executions: &broadcast::Sender<Execution>,
+    ) {
+        let lot_size = self.lot_size;
+        let shares = |quantity: usize| quantity * to / from / lot_size * lot_size;
+        let (to_ticks, from_ticks) = (to as Ticks, from as Ticks);
+        let round_down = |ticks: Ticks| (ticks * from_ticks / to_ticks).max(1);
+        let round_up = |ticks: Ticks| ((ticks * from_ticks + to_ticks - 1) / to_ticks).max(1);
+        let round = |ticks: Ticks| {
+            ((ticks * from_ticks) as f64 / to_ticks as f64)
+                .round()
+                .max(1.0) as Ticks
+        };
+
+        for mut bid in std::mem::take(&mut self.bids) {
+            let quantity = bid.remaining();
+            bid.price = Reverse(round_down(bid.price.0));
+            bid.display = bid.display.map(|display| shares(display).max(lot_size));
+            bid.set_remaining(shares(quantity));
+            let price = self.ticks_to_price(bid.price.0);
+            let _ = executions.send(Execution::of_bid(
+                sec_id,
+                &bid,
+                ExecKind::Adjusted,
+                price,
+                quantity,
+            ));
+            if bid.remaining() > 0 {
+                self.bids.push(bid);
+            }
+        }
+        for mut ask in std::mem::take(&mut self.asks) {
+            let quantity = ask.remaining();
+            ask.price = round_up(ask.price);
+            ask.display = ask.display.map(|display| shares(display).max(lot_size));
+            ask.set_remaining(shares(quantity));
+            let price = self.ticks_to_price(ask.price);
+            let _ = executions.send(Execution::of_ask(
+                sec_id,
+                &ask,
+                ExecKind::Adjusted,
+                price,
+                quantity,
+            ));
+            if ask.remaining() > 0 {
+                self.asks.push(ask);
+            }
+        }
+        for stop in self.stops.iter_mut() {
+            stop.trigger = round(stop.trigger);
+            stop.limit = stop.limit.map(|limit| match stop.side {
+                Side::Buy => round_down(limit),
+                Side::Sell => round_up(limit),
+            });
+            stop.quantity = shares(stop.quantity);
+        }
+        self.stops.retain(|stop| stop.quantity > 0);
+
+        let price_factor = from as f64 / to as f64;
+        self.last_trade = self.last_trade.scale_by(price_factor);
+        self.breaker.split(price_factor);
+        self.candles.split(to, from);
+        self.stats.split(to, from);
+        for dividend in self.dividends.iter_mut() {
+            // Holders recorded before the split are paid on the shares they had then
+            if dividend.holders.is_none() {
+                dividend.per_share = dividend.per_share.scale_by(price_factor);
+            }
+        }
+    }
+
+    /// Whether every price and quantity restated by a split of `from` shares into
+    /// `to` can be held
+    fn can_split(&self, to: usize, from: usize) -> bool {
+        let ticks = (self.bids.iter().map(|b| b.price.0))
+            .chain(self.asks.iter().map(|a| a.price))
+            .chain(
+                self.stops
+                    .iter()
+                    .flat_map(|s| [Some(s.trigger), s.limit])
+                    .flatten(),
+            )
+            .max()
+            .unwrap_or(0);
+        let quantity = (self
+            .bids
+            .iter()
+            .flat_map(|b| [b.remaining(), b.display.unwrap_or(0)]))
+        .chain(
+            self.asks
+                .iter()
+                .flat_map(|a| [a.remaining(), a.display.unwrap_or(0)]),
+        )
+        .chain(self.stops.iter().map(|s| s.quantity))
+        .chain([self.stats.volume, self.candles.max_volume()])
+        .max()
+        .unwrap_or(0);
+        ticks
+            .checked_mul(from as Ticks)
+            .and_then(|ticks| ticks.checked_add(to as Ticks))
+            .is_some()
+            && quantity.checked_mul(to).is_some()
+    }
+
+    /// Cancels and reports every order on the book and every stop waiting for its
+    /// trigger, as the security is delisted or the option series expired
+    fn cancel_orders(
+        &mut self,
+        sec_id: SecId,
+        kind: ExecKind,
+        executions: &broadcast::Sender<Execution>,
+    ) {
+        for mut bid in std::mem::take(&mut self.bids) {
+            let price = self.ticks_to_price(bid.price.0);
+            let quantity = bid.remaining();
+            bid.set_remaining(0);
+            let _ = executions.send(Execution::of_bid(sec_id, &bid, kind, price, quantity));
+        }
+        for mut ask in std::mem::take(&mut self.asks) {
+            let price = self.ticks_to_price(ask.price);
+            let quantity = ask.remaining();
+            ask.set_remaining(0);
+            let _ = executions.send(Execution::of_ask(sec_id, &ask, kind, price, quantity));
+        }
+        for mut stop in std::mem::take(&mut self.stops) {
+            info!(
+                "Canceled {:?} stop {} of account {} as security {} stopped trading",
+                stop.side, stop.id.0, stop.account.0, sec_id.0
+            );
+            let price = self.ticks_to_price(stop.trigger);
+            let quantity = stop.quantity;
+            stop.quantity = 0;
+            let _ = executions.send(Execution::of_stop(sec_id, &stop, kind, price, quantity));
+        }
+        self.buybacks.clear();
+    }
+
+    fn check_listed(&self, sec_id: SecId) -> Result<(), MarketError> {
+        if self.delisted.is_some() {
+            error!("Rejected change to delisted security {}", sec_id.0);
+            return Err(MarketError::Delisted(sec_id));
+        }
+        let expiry = match (&self.option, &self.future) {
+            (Some(option), _) => Some(option.expiry),
+            (_, Some(future)) => Some(future.expiry),
+            _ => None,
+        };
+        if let Some(expiry) = expiry.filter(|expiry| SystemTime::now() >= *expiry) {
+            error!(
+                "Rejected change to series {} expired at {:?}",
+                sec_id.0, expiry
+            );
+            return Err(MarketError::SeriesExpired(sec_id));
+        }
+        Ok(())
+    }
+
+    fn check_order_price(&self, sec_id: SecId, price: Money) -> Result<(), MarketError> {
+        self.check_listed(sec_id)?;
+        if self.breaker.is_halted(Instant::now()) {
+            error!(
+                "Rejected order at {} for halted security {}",
+                price, sec_id.0
+            );
+            return Err(MarketError::Halted(sec_id));
+        }
+        if !self.breaker.in_band(self.last_trade, price) {
+            let (low, high) = self.breaker.band(self.last_trade);
+            error!(
+                "Rejected order at {} for security {} outside price band {}..={}",
+                price, sec_id.0, low, high
+            );
+            return Err(MarketError::OutsidePriceBand {
+                sec: sec_id,
+                price,
+                low,
+                high,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeRate;
+
+    fn security(tick_size: i64, last_trade: i64) -> Security {
+        Security {
+            tick_size: Money(tick_size),
+            last_trade: Money(last_trade),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn prices_must_be_on_the_tick_grid() {
+        let sec_id = SecId(Uuid::new_v4());
+        let sec = security(50, 10_000);
+        assert_eq!(sec.price_to_ticks(sec_id, Money(10_050)).ok(), Some(201));
+        assert_eq!(sec.ticks_to_price(201), Money(10_050));
+        assert!(matches!(
+            sec.price_to_ticks(sec_id, Money(10_025)),
+            Err(MarketError::OffTick { .. })
+        ));
+        assert!(matches!(
+            sec.price_to_ticks(sec_id, Money::ZERO),
+            Err(MarketError::InvalidPrice { .. })
+        ));
+    }
+
+    #[test]
+    fn band_rounds_inwards_to_the_tick_grid() {
+        // The band of 8000 to 12000 holds ticks of 300 from 8100 to 12000
+        assert_eq!(security(300, 10_000).band_ticks(), (27, 40));
+        // but never reaches below the first tick
+        assert_eq!(security(300, 1).band_ticks(), (1, 0));
+    }
+
+    #[test]
+    fn bid_cost_includes_the_larger_fee() {
+        let sec = Security {
+            fees: vec![FeeSchedule {
+                maker: FeeRate::BasisPoints(10),
+                taker: FeeRate::BasisPoints(20),
+            }],
+            ..security(100, 10_000)
+        };
+        assert_eq!(sec.notional(Money(10_000), 100), Some(Money(1_000_000)));
+        assert_eq!(sec.bid_cost(Money(10_000), 100, 0), Some(Money(1_002_000)));
+        // Tiers past the last are charged as the last
+        assert_eq!(sec.bid_cost(Money(10_000), 100, 5), Some(Money(1_002_000)));
+        assert_eq!(sec.bid_cost(Money::MAX, 2, 0), None);
+    }
 }
@@ -0,0 +1,201 @@
+use std::{
+    fmt,
+    iter::Sum,
+    ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign},
+};
+
+/// Fixed-point amount of money, counted in minor units of `Money::SCALE` decimal places
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(pub i64);
+
+impl Money {
+    /// Number of decimal places kept by every amount
+    pub const SCALE: u32 = 4;
+    pub const ZERO: Money = Money(0);
+    pub const MAX: Money = Money(i64::MAX);
+
+    /// Converts an amount worth `units / 10^scale`, failing if it can not be held exactly
+    pub fn from_scaled(units: i64, scale: u32) -> Option<Self> {
+        if scale > Self::SCALE {
+            let divisor = 10i64.checked_pow(scale - Self::SCALE)?;
+            if units % divisor != 0 {
+                return None;
+            }
+            Some(Self(units / divisor))
+        } else {
+            let factor = 10i64.checked_pow(Self::SCALE - scale)?;
+            units.checked_mul(factor).map(Self)
+        }
+    }
+
+    /// Nearest amount to `value`
+    pub fn from_f64(value: f64) -> Self {
+        Self((value * 10f64.powi(Self::SCALE as i32)).round() as i64)
+    }
+
+    pub fn minor_units(self) -> i64 {
+        self.0
+    }
+
+    /// Approximate value, only meant for computing ratios
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / 10f64.powi(Self::SCALE as i32)
+    }
+
+    /// Multiplies by a fraction, rounding to the nearest minor unit
+    pub fn scale_by(self, factor: f64) -> Self {
+        Self((self.0 as f64 * factor).round() as i64)
+    }
+
+    /// Multiplies by a fraction, rounding down to a minor unit
+    pub fn scale_down(self, factor: f64) -> Self {
+        Self((self.0 as f64 * factor).floor() as i64)
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    /// Sum, None if it can not be held
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    /// Difference, None if it can not be held
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+
+    /// Product, None if it can not be held
+    pub fn checked_mul(self, rhs: i64) -> Option<Self> {
+        self.0.checked_mul(rhs).map(Self)
+    }
+
+    /// Sum, the nearest amount that can be held if it can not be
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Self(self.0.saturating_add(rhs.0))
+    }
+
+    /// Difference, the nearest amount that can be held if it can not be
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+
+    /// Product, the nearest amount that can be held if it can not be
+    pub fn saturating_mul(self, rhs: i64) -> Self {
+        Self(self.0.saturating_mul(rhs))
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let one = 10i64.pow(Self::SCALE);
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / one as u64,
+            abs % one as u64,
+            width = Self::SCALE as usize
+        )
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Money(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, rhs: Self) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Money(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, rhs: Self) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Self::Output {
+        Money(-self.0)
+    }
+}
+
+impl Mul<i64> for Money {
+    type Output = Money;
+
+    fn mul(self, rhs: i64) -> Self::Output {
+        Money(self.0 * rhs)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_scaled_only_accepts_exact_amounts() {
+        assert_eq!(Money::from_scaled(12, 0), Some(Money(120_000)));
+        assert_eq!(Money::from_scaled(12_340, 5), Some(Money(1_234)));
+        assert_eq!(Money::from_scaled(12_345, 5), None);
+        assert_eq!(Money::from_scaled(i64::MAX, 0), None);
+        assert_eq!(Money::from_scaled(1, 30), None);
+    }
+
+    #[test]
+    fn checked_ops_fail_instead_of_overflowing() {
+        assert_eq!(Money(5).checked_add(Money(7)), Some(Money(12)));
+        assert_eq!(Money::MAX.checked_add(Money(1)), None);
+        assert_eq!(Money(5).checked_sub(Money(7)), Some(Money(-2)));
+        assert_eq!(Money(i64::MIN).checked_sub(Money(1)), None);
+        assert_eq!(Money(5).checked_mul(-3), Some(Money(-15)));
+        assert_eq!(Money::MAX.checked_mul(2), None);
+    }
+
+    #[test]
+    fn saturating_ops_stop_at_the_limits() {
+        assert_eq!(Money::MAX.saturating_add(Money(1)), Money::MAX);
+        assert_eq!(Money(i64::MIN).saturating_sub(Money(1)), Money(i64::MIN));
+        assert_eq!(Money::MAX.saturating_mul(-2), Money(i64::MIN));
+        assert_eq!(Money(5).saturating_mul(3), Money(15));
+    }
+
+    #[test]
+    fn scale_by_rounds_and_scale_down_floors() {
+        assert_eq!(Money(10).scale_by(0.25), Money(3));
+        assert_eq!(Money(10).scale_down(0.25), Money(2));
+        assert_eq!(Money(-10).scale_by(0.25), Money(-3));
+        assert_eq!(Money(-10).scale_down(0.25), Money(-3));
+    }
+
+    #[test]
+    fn displays_every_decimal_place() {
+        assert_eq!(Money(12_345).to_string(), "1.2345");
+        assert_eq!(Money(-5).to_string(), "-0.0005");
+        assert_eq!(Money(i64::MIN).to_string(), "-922337203685477.5808");
+    }
+}
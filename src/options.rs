@@ -0,0 +1,170 @@
+use std::time::SystemTime;
+
+use crate::{money::Money, AccId, SecId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionKind {
+    /// Right to buy the underlying at the strike
+    Call,
+    /// Right to sell the underlying at the strike
+    Put,
+}
+
+/// Terms of an option series, listed as a security of its own. Accounts holding
+/// contracts short have written them.
+#[derive(Debug, Clone, Copy)]
+pub struct OptionContract {
+    pub underlying: SecId,
+    pub kind: OptionKind,
+    pub strike: Money,
+    pub expiry: SystemTime,
+    /// Shares of the underlying each contract is for
+    pub contract_size: usize,
+}
+
+impl OptionContract {
+    /// Whether the series is exercised when the underlying last traded at `price`
+    pub fn in_the_money(&self, price: Money) -> bool {
+        match self.kind {
+            OptionKind::Call => price > self.strike,
+            OptionKind::Put => price < self.strike,
+        }
+    }
+
+    /// Shares of the underlying delivered to and cash paid by a position of
+    /// `contracts` on exercise, both negative when going the other way
+    pub fn settlement(&self, contracts: i64) -> (i64, Money) {
+        let shares = contracts * self.contract_size as i64;
+        match self.kind {
+            OptionKind::Call => (shares, self.strike * shares),
+            OptionKind::Put => (-shares, self.strike * -shares),
+        }
+    }
+
+    /// Shares of the underlying and cash a position of `contracts` must be covered by
+    /// so that settling it never leaves a short position or unpaid strike: written
+    /// calls and held puts by the shares delivered, written puts by the strike paid.
+    /// None if more than can be held.
+    pub fn cover(&self, contracts: i64) -> Option<(i64, Money)> {
+        let shares = contracts.checked_mul(i64::try_from(self.contract_size).ok()?)?;
+        match self.kind {
+            OptionKind::Call if shares < 0 => Some((-shares, Money::ZERO)),
+            OptionKind::Call => Some((0, Money::ZERO)),
+            OptionKind::Put if shares < 0 => Some((0, self.strike.checked_mul(-shares)?)),
+            OptionKind::Put => Some((shares, Money::ZERO)),
+        }
+    }
+
+    /// Contracts each writer is assigned when only `exercised` of those in
+    /// `written` are exercised, in proportion to the contracts they wrote. What is
+    /// left over from rounding down goes to the largest writers first.
+    pub fn assign(written: &[(AccId, i64)], exercised: i64) -> Vec<(AccId, i64)> {
+        let total: i64 = written.iter().map(|(_, contracts)| contracts).sum();
+        let exercised = exercised.clamp(0, total.max(0));
+        let mut assigned: Vec<_> = written
+            .iter()
+            .map(|(acc_id, contracts)| {
+                let share = *contracts as i128 * exercised as i128 / total.max(1) as i128;
+                (*acc_id, share as i64)
+            })
+            .collect();
+        let mut left = exercised - assigned.iter().map(|(_, n)| n).sum::<i64>();
+        let mut largest: Vec<_> = (0..written.len()).collect();
+        largest.sort_by_key(|i| std::cmp::Reverse(written[*i].1));
+        for i in largest {
+            if left == 0 {
+                break;
+            }
+            if assigned[i].1 < written[i].1 {
+                assigned[i].1 += 1;
+                left -= 1;
+            }
+        }
+        assigned
+    }
+
+    /// Restates the terms after every `from` shares of the underlying became `to`,
+    /// dropping fractions of a share
+    pub fn split(&mut self, to: usize, from: usize) {
+        self.strike = self.strike.scale_by(from as f64 / to as f64);
+        self.contract_size = self.contract_size * to / from;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn contract(kind: OptionKind) -> OptionContract {
+        OptionContract {
+            underlying: SecId(Uuid::new_v4()),
+            kind,
+            strike: Money(100_000),
+            expiry: SystemTime::now(),
+            contract_size: 100,
+        }
+    }
+
+    #[test]
+    fn settlement_delivers_shares_against_the_strike() {
+        let call = contract(OptionKind::Call);
+        assert_eq!(call.settlement(2), (200, Money(20_000_000)));
+        assert_eq!(call.settlement(-1), (-100, Money(-10_000_000)));
+        let put = contract(OptionKind::Put);
+        assert_eq!(put.settlement(2), (-200, Money(-20_000_000)));
+        assert_eq!(put.settlement(-1), (100, Money(10_000_000)));
+    }
+
+    #[test]
+    fn in_the_money_only_past_the_strike() {
+        let call = contract(OptionKind::Call);
+        assert!(call.in_the_money(Money(100_001)));
+        assert!(!call.in_the_money(Money(100_000)));
+        let put = contract(OptionKind::Put);
+        assert!(put.in_the_money(Money(99_999)));
+        assert!(!put.in_the_money(Money(100_000)));
+    }
+
+    #[test]
+    fn cover_is_shares_or_cash_depending_on_the_side() {
+        let call = contract(OptionKind::Call);
+        assert_eq!(call.cover(-2), Some((200, Money::ZERO)));
+        assert_eq!(call.cover(2), Some((0, Money::ZERO)));
+        let put = contract(OptionKind::Put);
+        assert_eq!(put.cover(-2), Some((0, Money(20_000_000))));
+        assert_eq!(put.cover(2), Some((200, Money::ZERO)));
+        assert_eq!(put.cover(i64::MIN / 10), None);
+    }
+
+    #[test]
+    fn split_restates_strike_and_contract_size() {
+        let mut option = contract(OptionKind::Call);
+        option.split(2, 1);
+        assert_eq!(option.strike, Money(50_000));
+        assert_eq!(option.contract_size, 200);
+
+        let mut option = contract(OptionKind::Call);
+        option.split(1, 3);
+        assert_eq!(option.strike, Money(300_000));
+        assert_eq!(option.contract_size, 33);
+    }
+
+    #[test]
+    fn assign_splits_exercise_among_writers() {
+        let [a, b, c] = [(); 3].map(|_| AccId(Uuid::new_v4()));
+        let written = [(a, 5), (b, 3), (c, 2)];
+        assert_eq!(
+            OptionContract::assign(&written, 5),
+            vec![(a, 3), (b, 1), (c, 1)]
+        );
+        assert_eq!(OptionContract::assign(&written, 10), written.to_vec());
+        assert_eq!(OptionContract::assign(&written, 12), written.to_vec());
+        assert_eq!(
+            OptionContract::assign(&written, 0),
+            vec![(a, 0), (b, 0), (c, 0)]
+        );
+        assert!(OptionContract::assign(&[], 3).is_empty());
+    }
+}
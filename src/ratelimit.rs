@@ -0,0 +1,314 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use tonic::{
+    body::BoxBody,
+    codegen::http::{Request, Response},
+    metadata::MetadataValue,
+    transport::server::TcpConnectInfo,
+    Status,
+};
+use tower::{Layer, Service};
+
+use crate::AccId;
+
+/// Size and refill rate of each token bucket
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Requests that can be made at once after a quiet period
+    pub burst: f64,
+    /// Requests allowed each second on average
+    pub per_sec: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            burst: 50.0,
+            per_sec: 20.0,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Reads `STOK_RATE_BURST` and `STOK_RATE_PER_SEC`, keeping the default of either
+    /// that is unset or invalid
+    pub fn from_env() -> Self {
+        fn var(name: &str, default: f64) -> f64 {
+            match env::var(name).map(|value| value.parse::<f64>()) {
+                Ok(Ok(value)) if value > 0.0 => value,
+                Ok(_) => {
+                    warn!("Ignoring invalid value of {}", name);
+                    default
+                }
+                Err(_) => default,
+            }
+        }
+
+        let defaults = Self::default();
+        Self {
+            burst: var("STOK_RATE_BURST", defaults.burst),
+            per_sec: var("STOK_RATE_PER_SEC", defaults.per_sec),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.per_sec).min(config.burst);
+        self.updated = now;
+    }
+
+    /// Time until a token is available, if there is none now
+    fn wait(&self, config: &RateLimitConfig) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / config.per_sec))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RateKey {
+    Peer(IpAddr),
+    Account(AccId),
+}
+
+/// Token buckets in the order they were used, so those left idle long enough to
+/// have refilled are dropped from the front without looking at the rest
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<RateKey, TokenBucket>,
+    /// Every use of a bucket, oldest first. Uses followed by a later one of the same
+    /// bucket are skipped.
+    used: VecDeque<(RateKey, Instant)>,
+}
+
+impl Buckets {
+    /// Drops buckets unused for long enough to have refilled, as a new one would be
+    /// the same
+    fn evict(&mut self, config: &RateLimitConfig, now: Instant) {
+        let idle = Duration::from_secs_f64(config.burst / config.per_sec);
+        while let Some((key, used)) = self.used.front() {
+            if self.buckets.get(key).is_some_and(|b| b.updated == *used) {
+                if now.duration_since(*used) < idle {
+                    break;
+                }
+                self.buckets.remove(key);
+            }
+            self.used.pop_front();
+        }
+    }
+}
+
+/// Limits requests of each connecting address with token buckets, and those
+/// acting for each account once the service has checked it exists
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    config: RateLimitConfig,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a token from the bucket of every key, or none of them if any is empty.
+    /// Returns how long to wait before retrying if the request is refused.
+    fn take(&self, keys: &[RateKey]) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.evict(&self.config, now);
+
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let bucket = buckets.buckets.entry(key.clone()).or_insert(TokenBucket {
+                tokens: self.config.burst,
+                updated: now,
+            });
+            bucket.refill(&self.config, now);
+            wait = wait.max(bucket.wait(&self.config).unwrap_or_default());
+            buckets.used.push_back((key.clone(), now));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for key in keys {
+            buckets.buckets.get_mut(key).unwrap().tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Takes a token from the bucket of an account a request acts for. Called by the
+    /// service only for accounts that exist, as a client could otherwise name a new
+    /// one with every request.
+    pub fn take_account(&self, acc: AccId) -> Result<(), Duration> {
+        self.take(&[RateKey::Account(acc)]).inspect_err(|wait| {
+            debug!("Refused request for account {}; retry in {:?}", acc.0, wait);
+        })
+    }
+}
+
+/// Status telling the client to retry after `wait`
+pub fn refused(wait: Duration) -> Status {
+    let mut status = Status::resource_exhausted(format!(
+        "Rate limit exceeded, retry in {} ms",
+        wait.as_millis()
+    ));
+    let metadata = status.metadata_mut();
+    metadata.insert(
+        "grpc-retry-pushback-ms",
+        MetadataValue::from(wait.as_millis() as u64),
+    );
+    metadata.insert(
+        "retry-after",
+        MetadataValue::from(wait.as_secs_f64().ceil() as u64),
+    );
+    status
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: RateLimitLayer,
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for RateLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let mut keys = Vec::with_capacity(1);
+        if let Some(addr) = req
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|info| info.remote_addr())
+        {
+            keys.push(RateKey::Peer(addr.ip()));
+        }
+
+        match self.limiter.take(&keys) {
+            Ok(()) => Box::pin(self.inner.call(req)),
+            Err(wait) => {
+                debug!(
+                    "Refused {} from {:?}; retry in {:?}",
+                    req.uri().path(),
+                    keys,
+                    wait
+                );
+                let status = refused(wait);
+                Box::pin(async move { Ok(status.to_http()) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    const CONFIG: RateLimitConfig = RateLimitConfig {
+        burst: 2.0,
+        per_sec: 1.0,
+    };
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: 0.5,
+            updated: now,
+        };
+        assert_eq!(bucket.wait(&CONFIG), Some(Duration::from_millis(500)));
+        bucket.refill(&CONFIG, now + Duration::from_secs(1));
+        assert_eq!(bucket.tokens, 1.5);
+        assert_eq!(bucket.wait(&CONFIG), None);
+        bucket.refill(&CONFIG, now + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, CONFIG.burst);
+    }
+
+    #[test]
+    fn refuses_once_the_burst_is_spent() {
+        let limiter = RateLimitLayer::new(CONFIG);
+        let [a, b] = [(); 2].map(|_| AccId(Uuid::new_v4()));
+        assert!(limiter.take_account(a).is_ok());
+        assert!(limiter.take_account(a).is_ok());
+        let wait = limiter.take_account(a).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        assert!(limiter.take_account(b).is_ok());
+    }
+
+    #[test]
+    fn refused_requests_take_no_tokens() {
+        let limiter = RateLimitLayer::new(CONFIG);
+        let [a, b] = [(); 2].map(|_| RateKey::Account(AccId(Uuid::new_v4())));
+        assert!(limiter.take(std::slice::from_ref(&a)).is_ok());
+        assert!(limiter.take(std::slice::from_ref(&a)).is_ok());
+        assert!(limiter.take(&[a, b.clone()]).is_err());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets[&b].tokens, CONFIG.burst);
+    }
+
+    #[test]
+    fn idle_buckets_are_evicted() {
+        let limiter = RateLimitLayer::new(CONFIG);
+        let acc = AccId(Uuid::new_v4());
+        limiter.take_account(acc).unwrap();
+        let mut buckets = limiter.buckets.lock().unwrap();
+        buckets.evict(&CONFIG, Instant::now());
+        assert_eq!(buckets.buckets.len(), 1);
+        buckets.evict(&CONFIG, Instant::now() + Duration::from_secs(3));
+        assert!(buckets.buckets.is_empty());
+        assert!(buckets.used.is_empty());
+    }
+
+    #[test]
+    fn refusal_tells_the_client_when_to_retry() {
+        let status = refused(Duration::from_millis(1_500));
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(
+            status.metadata().get("grpc-retry-pushback-ms").unwrap(),
+            "1500"
+        );
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "2");
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    fmt::Debug,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{bidask::Side, currency::Currency, market::MarketError, money::Money, AccId, SecId};
+
+/// Order about to be accepted by the market
+#[derive(Debug, Clone)]
+pub struct OrderRequest {
+    pub account: AccId,
+    pub sec: SecId,
+    pub side: Side,
+    pub price: Money,
+    pub quantity: usize,
+    /// Worth of the order at `price`, contract multipliers included
+    pub value: Money,
+    /// Currency of the security, which `value` is in
+    pub currency: Currency,
+}
+
+/// What the market knows about the account placing an order
+#[derive(Debug, Clone, Copy)]
+pub struct RiskContext {
+    /// Orders of the account on the book or waiting for a trigger, in every security
+    pub open_orders: usize,
+    /// Shares of the security held by the account, negative if it is short
+    pub position: i64,
+    /// Value of the order in the base currency
+    pub notional: Money,
+}
+
+/// Check run before an order is accepted, rejecting it if it is too risky
+pub trait RiskCheck: Debug + Send + Sync {
+    fn check(&self, order: &OrderRequest, context: &RiskContext) -> Result<(), MarketError>;
+}
+
+#[derive(Debug)]
+pub struct MaxOrderSize(pub usize);
+
+impl RiskCheck for MaxOrderSize {
+    fn check(&self, order: &OrderRequest, _context: &RiskContext) -> Result<(), MarketError> {
+        if order.quantity > self.0 {
+            return Err(MarketError::OrderTooLarge {
+                quantity: order.quantity,
+                max: self.0,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MaxNotional(pub Money);
+
+impl RiskCheck for MaxNotional {
+    fn check(&self, _order: &OrderRequest, context: &RiskContext) -> Result<(), MarketError> {
+        if context.notional > self.0 {
+            return Err(MarketError::NotionalTooLarge {
+                notional: context.notional,
+                max: self.0,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MaxOpenOrders(pub usize);
+
+impl RiskCheck for MaxOpenOrders {
+    fn check(&self, order: &OrderRequest, context: &RiskContext) -> Result<(), MarketError> {
+        if context.open_orders >= self.0 {
+            return Err(MarketError::TooManyOpenOrders {
+                acc: order.account,
+                max: self.0,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Largest number of shares an account may hold long or short in one security,
+/// counting the order as filled
+#[derive(Debug)]
+pub struct MaxPosition(pub usize);
+
+impl RiskCheck for MaxPosition {
+    fn check(&self, order: &OrderRequest, context: &RiskContext) -> Result<(), MarketError> {
+        let position = match order.side {
+            Side::Buy => context.position.saturating_add(order.quantity as i64),
+            Side::Sell => context.position.saturating_sub(order.quantity as i64),
+        };
+        if position.unsigned_abs() as usize > self.0 {
+            return Err(MarketError::PositionLimit {
+                acc: order.account,
+                sec: order.sec,
+                position,
+                max: self.0,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Largest number of orders an account may place within any second
+#[derive(Debug)]
+pub struct MaxOrderRate {
+    max: usize,
+    recent: Mutex<HashMap<AccId, VecDeque<Instant>>>,
+}
+
+impl MaxOrderRate {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            recent: Default::default(),
+        }
+    }
+}
+
+impl RiskCheck for MaxOrderRate {
+    fn check(&self, order: &OrderRequest, _context: &RiskContext) -> Result<(), MarketError> {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        let placed = recent.entry(order.account).or_default();
+        while let Some(time) = placed.front() {
+            if now.duration_since(*time) >= Duration::from_secs(1) {
+                placed.pop_front();
+            } else {
+                break;
+            }
+        }
+        if placed.len() >= self.max {
+            return Err(MarketError::OrderRateExceeded {
+                acc: order.account,
+                max: self.max,
+            });
+        }
+        placed.push_back(now);
+        Ok(())
+    }
+}
+
+/// Limits applied to every order, each left off when unset
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RiskLimits {
+    pub max_order_size: Option<usize>,
+    pub max_notional: Option<Money>,
+    pub max_open_orders: Option<usize>,
+    pub max_position: Option<usize>,
+    pub max_orders_per_sec: Option<usize>,
+}
+
+impl RiskLimits {
+    /// Reads limits from `STOK_MAX_ORDER_SIZE`, `STOK_MAX_NOTIONAL` (in whole units
+    /// of the base currency), `STOK_MAX_OPEN_ORDERS`, `STOK_MAX_POSITION` and
+    /// `STOK_MAX_ORDERS_PER_SEC`
+    pub fn from_env() -> Self {
+        fn var(name: &str) -> Option<usize> {
+            let value = env::var(name).ok()?;
+            match value.parse() {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    warn!("Ignoring invalid value {:?} of {}", value, name);
+                    None
+                }
+            }
+        }
+
+        Self {
+            max_order_size: var("STOK_MAX_ORDER_SIZE"),
+            max_notional: var("STOK_MAX_NOTIONAL")
+                .and_then(|units| Money::from_scaled(units as i64, 0)),
+            max_open_orders: var("STOK_MAX_OPEN_ORDERS"),
+            max_position: var("STOK_MAX_POSITION"),
+            max_orders_per_sec: var("STOK_MAX_ORDERS_PER_SEC"),
+        }
+    }
+
+    /// Checks enforcing the limits that are set. The order rate is checked last, so
+    /// orders rejected by another check do not count towards it.
+    pub fn checks(&self) -> Vec<Box<dyn RiskCheck>> {
+        let mut checks: Vec<Box<dyn RiskCheck>> = Vec::new();
+        if let Some(max) = self.max_order_size {
+            checks.push(Box::new(MaxOrderSize(max)));
+        }
+        if let Some(max) = self.max_notional {
+            checks.push(Box::new(MaxNotional(max)));
+        }
+        if let Some(max) = self.max_open_orders {
+            checks.push(Box::new(MaxOpenOrders(max)));
+        }
+        if let Some(max) = self.max_position {
+            checks.push(Box::new(MaxPosition(max)));
+        }
+        if let Some(max) = self.max_orders_per_sec {
+            checks.push(Box::new(MaxOrderRate::new(max)));
+        }
+        checks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn order(account: AccId, side: Side, quantity: usize) -> OrderRequest {
+        OrderRequest {
+            account,
+            sec: SecId(Uuid::new_v4()),
+            side,
+            price: Money(10_000),
+            quantity,
+            value: Money(10_000 * quantity as i64),
+            currency: Currency::default(),
+        }
+    }
+
+    fn context(open_orders: usize, position: i64, notional: Money) -> RiskContext {
+        RiskContext {
+            open_orders,
+            position,
+            notional,
+        }
+    }
+
+    #[test]
+    fn size_notional_and_open_orders_are_capped() {
+        let acc = AccId(Uuid::new_v4());
+        let ctx = context(2, 0, Money(50_000));
+        assert!(MaxOrderSize(5)
+            .check(&order(acc, Side::Buy, 5), &ctx)
+            .is_ok());
+        assert!(matches!(
+            MaxOrderSize(5).check(&order(acc, Side::Buy, 6), &ctx),
+            Err(MarketError::OrderTooLarge {
+                quantity: 6,
+                max: 5
+            })
+        ));
+        assert!(MaxNotional(Money(50_000))
+            .check(&order(acc, Side::Buy, 5), &ctx)
+            .is_ok());
+        assert!(matches!(
+            MaxNotional(Money(49_999)).check(&order(acc, Side::Buy, 5), &ctx),
+            Err(MarketError::NotionalTooLarge { .. })
+        ));
+        assert!(MaxOpenOrders(3)
+            .check(&order(acc, Side::Buy, 1), &ctx)
+            .is_ok());
+        assert!(matches!(
+            MaxOpenOrders(2).check(&order(acc, Side::Buy, 1), &ctx),
+            Err(MarketError::TooManyOpenOrders { max: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn position_counts_the_order_as_filled() {
+        let acc = AccId(Uuid::new_v4());
+        let check = MaxPosition(10);
+        let long = context(0, 5, Money::ZERO);
+        assert!(check.check(&order(acc, Side::Buy, 5), &long).is_ok());
+        assert!(matches!(
+            check.check(&order(acc, Side::Buy, 6), &long),
+            Err(MarketError::PositionLimit { position: 11, .. })
+        ));
+        assert!(check.check(&order(acc, Side::Sell, 15), &long).is_ok());
+        assert!(matches!(
+            check.check(&order(acc, Side::Sell, 16), &long),
+            Err(MarketError::PositionLimit { position: -11, .. })
+        ));
+    }
+
+    #[test]
+    fn order_rate_is_per_account() {
+        let [a, b] = [(); 2].map(|_| AccId(Uuid::new_v4()));
+        let check = MaxOrderRate::new(2);
+        let ctx = context(0, 0, Money::ZERO);
+        assert!(check.check(&order(a, Side::Buy, 1), &ctx).is_ok());
+        assert!(check.check(&order(a, Side::Sell, 1), &ctx).is_ok());
+        assert!(matches!(
+            check.check(&order(a, Side::Buy, 1), &ctx),
+            Err(MarketError::OrderRateExceeded { max: 2, .. })
+        ));
+        assert!(check.check(&order(b, Side::Buy, 1), &ctx).is_ok());
+    }
+
+    #[test]
+    fn only_limits_set_are_checked() {
+        assert!(RiskLimits::default().checks().is_empty());
+        let limits = RiskLimits {
+            max_order_size: Some(10),
+            max_orders_per_sec: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(limits.checks().len(), 2);
+    }
+}
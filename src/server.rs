@@ -10,14 +10,18 @@ use std::{
     pin::Pin,
     sync::Arc,
     thread::{self, JoinHandle, Thread},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 use stok::*;
 use thiserror::Error;
 use tokio::{
     join,
     runtime::Builder,
-    sync::watch::{self, Receiver},
+    sync::{
+        broadcast::error::RecvError,
+        mpsc,
+        watch::{self, Receiver},
+    },
     time::{interval, Interval},
 };
 use tokio_stream::{
@@ -27,30 +31,544 @@ use tokio_stream::{
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 use uuid::Uuid;
+mod account;
+mod actions;
+mod basket;
 mod bidask;
+mod breaker;
+mod candles;
+mod currency;
+mod execution;
+mod fees;
+mod futures;
+mod index;
 mod market;
-use crate::market::Market;
+mod money;
+mod options;
+mod ratelimit;
+mod risk;
+mod stats;
+use crate::actions::{ActionKind, CorporateAction};
+use crate::breaker::BreakerConfig;
+use crate::currency::{Currency, FxRates};
+use crate::execution::{ExecKind, Execution};
+use crate::fees::FeeSchedule;
+use crate::index::{IndexConfig, Weighting};
+use crate::market::{Market, SecurityConfig, ShortSelling};
+use crate::money::Money;
 use tonic::{transport::Server, Request, Response, Status};
 
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct SecId(Uuid);
 #[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct AccId(Uuid);
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct OrderId(Uuid);
 
 pub mod stok {
     tonic::include_proto!("stok"); // The string specified here must match the proto package name
 }
 
+impl From<Money> for stok::Money {
+    fn from(value: Money) -> Self {
+        stok::Money {
+            units: value.minor_units(),
+            scale: Money::SCALE,
+        }
+    }
+}
+
+impl From<SecId> for stok::SecId {
+    fn from(value: SecId) -> Self {
+        stok::SecId {
+            id: Some(stok::Uuid {
+                value: value.0.to_string(),
+            }),
+        }
+    }
+}
+
+impl From<AccId> for stok::AccId {
+    fn from(value: AccId) -> Self {
+        stok::AccId {
+            id: Some(stok::Uuid {
+                value: value.0.to_string(),
+            }),
+        }
+    }
+}
+
+impl From<OrderId> for stok::OrderId {
+    fn from(value: OrderId) -> Self {
+        stok::OrderId {
+            id: Some(stok::Uuid {
+                value: value.0.to_string(),
+            }),
+        }
+    }
+}
+
+impl From<market::DepthLevel> for stok::DepthLevel {
+    fn from(value: market::DepthLevel) -> Self {
+        stok::DepthLevel {
+            price: Some(value.price.into()),
+            quantity: value.quantity as u64,
+            orders: value.orders as u64,
+        }
+    }
+}
+
+impl From<account::MarginCall> for stok::MarginCall {
+    fn from(value: account::MarginCall) -> Self {
+        stok::MarginCall {
+            acc: Some(value.account.into()),
+            equity: Some(value.equity.into()),
+            requirement: Some(value.requirement.into()),
+        }
+    }
+}
+
+impl From<execution::Trade> for stok::Trade {
+    fn from(value: execution::Trade) -> Self {
+        let taker = match value.taker {
+            bidask::Side::Buy => stok::Side::Buy,
+            bidask::Side::Sell => stok::Side::Sell,
+        };
+        stok::Trade {
+            time: Some(value.time.into()),
+            price: Some(value.price.into()),
+            quantity: value.quantity as u64,
+            buyer: Some(value.buyer.into()),
+            seller: Some(value.seller.into()),
+            bid: Some(value.bid.into()),
+            ask: Some(value.ask.into()),
+            taker: taker.into(),
+            buyer_fee: Some(value.buyer_fee.into()),
+            seller_fee: Some(value.seller_fee.into()),
+        }
+    }
+}
+
+impl From<candles::CandleInterval> for stok::CandleInterval {
+    fn from(value: candles::CandleInterval) -> Self {
+        match value {
+            candles::CandleInterval::OneSecond => stok::CandleInterval::OneSecond,
+            candles::CandleInterval::OneMinute => stok::CandleInterval::OneMinute,
+            candles::CandleInterval::FiveMinutes => stok::CandleInterval::FiveMinutes,
+            candles::CandleInterval::OneHour => stok::CandleInterval::OneHour,
+            candles::CandleInterval::OneDay => stok::CandleInterval::OneDay,
+        }
+    }
+}
+
+impl From<stok::CandleInterval> for candles::CandleInterval {
+    fn from(value: stok::CandleInterval) -> Self {
+        match value {
+            stok::CandleInterval::OneSecond => candles::CandleInterval::OneSecond,
+            stok::CandleInterval::OneMinute => candles::CandleInterval::OneMinute,
+            stok::CandleInterval::FiveMinutes => candles::CandleInterval::FiveMinutes,
+            stok::CandleInterval::OneHour => candles::CandleInterval::OneHour,
+            stok::CandleInterval::OneDay => candles::CandleInterval::OneDay,
+        }
+    }
+}
+
+impl From<(SecId, candles::Candle)> for stok::Candle {
+    fn from((sec, candle): (SecId, candles::Candle)) -> Self {
+        stok::Candle {
+            sec: Some(sec.into()),
+            interval: stok::CandleInterval::from(candle.interval).into(),
+            start: Some(candle.start.into()),
+            open: Some(candle.open.into()),
+            high: Some(candle.high.into()),
+            low: Some(candle.low.into()),
+            close: Some(candle.close.into()),
+            volume: candle.volume as u64,
+        }
+    }
+}
+
+impl From<Weighting> for stok::Weighting {
+    fn from(value: Weighting) -> Self {
+        match value {
+            Weighting::MarketCap => stok::Weighting::MarketCap,
+            Weighting::Price => stok::Weighting::Price,
+            Weighting::Equal => stok::Weighting::Equal,
+        }
+    }
+}
+
+impl From<index::Index> for IndexValue {
+    fn from(value: index::Index) -> Self {
+        IndexValue {
+            value: value.value,
+            weighting: stok::Weighting::from(value.config.weighting).into(),
+            members: value.members().map(|sec| (*sec).into()).collect(),
+            name: value.config.name,
+        }
+    }
+}
+
+impl From<CorporateAction> for stok::CorporateAction {
+    fn from(value: CorporateAction) -> Self {
+        let kind = match value.kind {
+            ActionKind::Split { to, from } => corporate_action::Kind::Split(Split {
+                to: to as u64,
+                from: from as u64,
+            }),
+            ActionKind::Dividend {
+                per_share,
+                record,
+                pay,
+            } => corporate_action::Kind::Dividend(stok::Dividend {
+                per_share: Some(per_share.into()),
+                record_time: Some(record.into()),
+                pay_time: Some(pay.into()),
+            }),
+            ActionKind::Issue { quantity, price } => corporate_action::Kind::Issue(Issue {
+                quantity: quantity as u64,
+                price: price.map(Into::into),
+            }),
+            ActionKind::Buyback { quantity, price } => corporate_action::Kind::Buyback(Buyback {
+                quantity: quantity as u64,
+                price: price.map(Into::into),
+            }),
+            ActionKind::Delisting { price } => corporate_action::Kind::Delisting(Delisting {
+                price: price.map(Into::into),
+            }),
+            ActionKind::Expiry { price, exercised } => corporate_action::Kind::Expiry(Expiry {
+                price: Some(price.into()),
+                exercised,
+            }),
+            ActionKind::FinalSettlement { price } => {
+                corporate_action::Kind::FinalSettlement(FinalSettlement {
+                    price: Some(price.into()),
+                })
+            }
+        };
+        stok::CorporateAction {
+            sec: Some(value.sec.into()),
+            time: Some(value.time.into()),
+            kind: Some(kind),
+        }
+    }
+}
+
+impl From<FxRates> for stok::FxRates {
+    fn from(value: FxRates) -> Self {
+        stok::FxRates {
+            base: Currency::BASE.to_string(),
+            rates: value
+                .rates()
+                .map(|(currency, rate)| FxRate {
+                    currency: currency.to_string(),
+                    rate,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<account::StatementEntry> for stok::StatementEntry {
+    fn from(value: account::StatementEntry) -> Self {
+        let (r#type, sec, shares, per_share) = match value.kind {
+            account::EntryKind::Deposit => (EntryType::Deposit, None, 0, None),
+            account::EntryKind::Conversion => (EntryType::Conversion, None, 0, None),
+            account::EntryKind::Dividend {
+                sec,
+                shares,
+                per_share,
+            } => (EntryType::Dividend, Some(sec), shares, Some(per_share)),
+            account::EntryKind::CashInLieu { sec } => (EntryType::CashInLieu, Some(sec), 0, None),
+            account::EntryKind::CashOut { sec, shares, price } => {
+                (EntryType::CashOut, Some(sec), shares, Some(price))
+            }
+            account::EntryKind::Exercise {
+                sec,
+                contracts,
+                strike,
+            } => (EntryType::Exercise, Some(sec), contracts, Some(strike)),
+            account::EntryKind::VariationMargin {
+                sec,
+                contracts,
+                price,
+            } => (
+                EntryType::VariationMargin,
+                Some(sec),
+                contracts,
+                Some(price),
+            ),
+        };
+        stok::StatementEntry {
+            time: Some(value.time.into()),
+            r#type: r#type.into(),
+            amount: Some(value.amount.into()),
+            balance: Some(value.balance.into()),
+            sec: sec.map(Into::into),
+            shares,
+            per_share: per_share.map(Into::into),
+            currency: value.currency.to_string(),
+        }
+    }
+}
+
+impl From<Execution> for ExecutionReport {
+    fn from(value: Execution) -> Self {
+        let side = match value.side {
+            bidask::Side::Buy => stok::Side::Buy,
+            bidask::Side::Sell => stok::Side::Sell,
+        };
+        let kind = match value.kind {
+            ExecKind::Fill => ExecType::Fill,
+            ExecKind::SelfTradeCanceled => ExecType::SelfTradeCanceled,
+            ExecKind::SelfTradeDecremented => ExecType::SelfTradeDecremented,
+            ExecKind::StopTriggered => ExecType::StopTriggered,
+            ExecKind::Replenished => ExecType::Replenished,
+            ExecKind::Expired => ExecType::Expired,
+            ExecKind::BuyIn => ExecType::BuyIn,
+            ExecKind::Liquidation => ExecType::Liquidation,
+            ExecKind::Adjusted => ExecType::Adjusted,
+            ExecKind::Delisted => ExecType::Delisted,
+            ExecKind::Overflowed => ExecType::Overflowed,
+            ExecKind::Canceled => ExecType::Canceled,
+            ExecKind::Rejected => ExecType::Rejected,
+            ExecKind::Placed => ExecType::Placed,
+        };
+        ExecutionReport {
+            acc: Some(value.account.into()),
+            sec: Some(value.sec.into()),
+            order: Some(value.order.into()),
+            side: side.into(),
+            kind: kind.into(),
+            price: Some(value.price.into()),
+            quantity: value.quantity as u64,
+            remaining: value.remaining as u64,
+            fee: Some(value.fee.into()),
+            entry: None,
+        }
+    }
+}
+
+impl From<(AccId, account::StatementEntry)> for ExecutionReport {
+    fn from((acc, entry): (AccId, account::StatementEntry)) -> Self {
+        let entry: stok::StatementEntry = entry.into();
+        ExecutionReport {
+            acc: Some(acc.into()),
+            sec: entry.sec.clone(),
+            kind: ExecType::Posted.into(),
+            entry: Some(entry),
+            ..Default::default()
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_uuid(id: Option<stok::Uuid>, name: &str) -> Result<Uuid, Status> {
+    if let Some(id) = id {
+        Uuid::parse_str(&id.value)
+            .map_err(|_| Status::data_loss(format!("Invalid {} ID sent: {}", name, id.value)))
+    } else {
+        Err(Status::data_loss(format!("No {} ID sent", name)))
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_money(money: Option<stok::Money>, name: &str) -> Result<Money, Status> {
+    let money = money.ok_or_else(|| Status::data_loss(format!("No {} sent", name)))?;
+    Money::from_scaled(money.units, money.scale).ok_or_else(|| {
+        Status::invalid_argument(format!(
+            "Invalid {} sent: {} / 10^{} can not be held to {} decimal places",
+            name,
+            money.units,
+            money.scale,
+            Money::SCALE
+        ))
+    })
+}
+
+/// Number of shares, rejecting more than an account can hold
+#[allow(clippy::result_large_err)]
+fn parse_quantity(quantity: u64, name: &str) -> Result<usize, Status> {
+    i64::try_from(quantity)
+        .map(|quantity| quantity as usize)
+        .map_err(|_| {
+            Status::invalid_argument(format!(
+                "Invalid {} sent: {} shares can not be held",
+                name, quantity
+            ))
+        })
+}
+
+/// Currency of a three letter code, None when empty
+#[allow(clippy::result_large_err)]
+fn parse_currency(code: &str, name: &str) -> Result<Option<Currency>, Status> {
+    if code.is_empty() {
+        return Ok(None);
+    }
+    Currency::new(code)
+        .map(Some)
+        .ok_or_else(|| Status::invalid_argument(format!("Invalid {} sent: {}", name, code)))
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_timestamp(
+    timestamp: Option<prost_types::Timestamp>,
+    name: &str,
+) -> Result<Option<SystemTime>, Status> {
+    match timestamp {
+        Some(timestamp) => SystemTime::try_from(timestamp.clone())
+            .map(Some)
+            .map_err(|_| Status::invalid_argument(format!("Invalid {} sent: {}", name, timestamp))),
+        None => Ok(None),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_option(option: OptionTerms) -> Result<options::OptionContract, Status> {
+    let kind = match option.r#type() {
+        OptionType::Call => options::OptionKind::Call,
+        OptionType::Put => options::OptionKind::Put,
+    };
+    Ok(options::OptionContract {
+        underlying: SecId(parse_uuid(
+            option.underlying.and_then(|s| s.id),
+            "underlying security",
+        )?),
+        kind,
+        strike: parse_money(option.strike, "strike")?,
+        expiry: parse_timestamp(option.expiry, "option expiry")?
+            .ok_or_else(|| Status::data_loss("No option expiry sent".to_string()))?,
+        contract_size: option.contract_size as usize,
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_future(future: FutureTerms) -> Result<futures::FutureContract, Status> {
+    let underlying = match future.underlying {
+        Some(future_terms::Underlying::Sec(sec)) => {
+            futures::Underlying::Security(SecId(parse_uuid(sec.id, "underlying security")?))
+        }
+        Some(future_terms::Underlying::Index(name)) => futures::Underlying::Index(name),
+        None => return Err(Status::data_loss("No underlying sent".to_string())),
+    };
+    Ok(futures::FutureContract {
+        underlying,
+        expiry: parse_timestamp(future.expiry, "futures expiry")?
+            .ok_or_else(|| Status::data_loss("No futures expiry sent".to_string()))?,
+        multiplier: future.multiplier as usize,
+        initial_margin: parse_money(future.initial_margin, "initial margin")?,
+        maintenance_margin: parse_money(future.maintenance_margin, "maintenance margin")?,
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_time_in_force(
+    time_in_force: stok::TimeInForce,
+    expires_at: Option<prost_types::Timestamp>,
+) -> Result<bidask::TimeInForce, Status> {
+    match time_in_force {
+        stok::TimeInForce::GoodTillCancel => Ok(bidask::TimeInForce::GoodTillCancel),
+        stok::TimeInForce::Day => Ok(bidask::TimeInForce::Day),
+        stok::TimeInForce::GoodTillTime => parse_timestamp(expires_at, "expiry time")?
+            .map(bidask::TimeInForce::GoodTillTime)
+            .ok_or_else(|| Status::data_loss("No expiry time sent".to_string())),
+    }
+}
+
+/// Fee rate, rejecting negative fees and more than the whole value of a fill
+#[allow(clippy::result_large_err)]
+fn parse_fee_rate(rate: Option<stok::FeeRate>, name: &str) -> Result<fees::FeeRate, Status> {
+    match rate.and_then(|r| r.rate) {
+        Some(stok::fee_rate::Rate::Flat(fee)) => {
+            let fee = parse_money(Some(fee), name)?;
+            if fee < Money::ZERO {
+                return Err(Status::invalid_argument(format!(
+                    "Invalid {} sent: {} is negative",
+                    name, fee
+                )));
+            }
+            Ok(fees::FeeRate::Flat(fee))
+        }
+        Some(stok::fee_rate::Rate::BasisPoints(bps)) => {
+            if !(0..=10_000).contains(&bps) {
+                return Err(Status::invalid_argument(format!(
+                    "Invalid {} sent: {} basis points is not between 0 and 10000",
+                    name, bps
+                )));
+            }
+            Ok(fees::FeeRate::BasisPoints(bps))
+        }
+        None => Ok(fees::FeeRate::default()),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_fee_schedule(schedule: stok::FeeSchedule) -> Result<FeeSchedule, Status> {
+    Ok(FeeSchedule {
+        maker: parse_fee_rate(schedule.maker, "maker fee")?,
+        taker: parse_fee_rate(schedule.taker, "taker fee")?,
+    })
+}
+
 type ResponseStream = Pin<Box<dyn Stream<Item = Result<SecValue, Status>> + Send>>;
+type ExecutionStream = Pin<Box<dyn Stream<Item = Result<ExecutionReport, Status>> + Send>>;
+type CandleStream = Pin<Box<dyn Stream<Item = Result<stok::Candle, Status>> + Send>>;
+type IndexStream = Pin<Box<dyn Stream<Item = Result<IndexValue, Status>> + Send>>;
+type CorporateActionStream =
+    Pin<Box<dyn Stream<Item = Result<stok::CorporateAction, Status>> + Send>>;
+type MarginCallStream = Pin<Box<dyn Stream<Item = Result<stok::MarginCall, Status>> + Send>>;
+
+/// Header carrying the token that allows admin operations
+const ADMIN_HEADER: &str = "admin-token";
+
+/// Trades listed when a request does not say how many
+const DEFAULT_TRADES: usize = 100;
 
 #[derive(Debug)]
 pub struct MyGreeter {
     market: crate::market::Market,
+    /// Token expected in the `admin-token` header, admin operations are refused
+    /// when unset
+    admin_token: Option<String>,
+    /// Shared with the layer limiting each connecting address
+    rate_limit: ratelimit::RateLimitLayer,
+}
+
+impl MyGreeter {
+    #[allow(clippy::result_large_err)]
+    /// Limits requests acting for an account, which has to exist so the limit can
+    /// not be dodged by naming a new account each time
+    fn limit_account(&self, acc: AccId) -> Result<(), Status> {
+        if !self.market.has_account(acc) {
+            return Err(market::MarketError::AccDoesNotExist(acc).into());
+        }
+        self.rate_limit
+            .take_account(acc)
+            .map_err(ratelimit::refused)
+    }
+
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(expected) = &self.admin_token else {
+            return Err(Status::permission_denied("Admin operations are disabled"));
+        };
+        match request.metadata().get(ADMIN_HEADER) {
+            Some(token) if token.as_bytes() == expected.as_bytes() => Ok(()),
+            _ => {
+                warn!("Refused admin operation without a valid token");
+                Err(Status::permission_denied("Invalid admin token"))
+            }
+        }
+    }
 }
 
 #[tonic::async_trait]
 impl market_server::Market for MyGreeter {
     type RegisterSecValueStream = ResponseStream;
+    type SubscribeExecutionsStream = ExecutionStream;
+    type SubscribeMarginCallsStream = MarginCallStream;
+    type SubscribeCandlesStream = CandleStream;
+    type SubscribeIndexStream = IndexStream;
+    type SubscribeCorporateActionsStream = CorporateActionStream;
 
     async fn list_securities(
         &self,
@@ -72,9 +590,17 @@ impl market_server::Market for MyGreeter {
 
     async fn create_account(
         &self,
-        _request: tonic::Request<CreateAccReq>,
+        request: tonic::Request<CreateAccReq>,
     ) -> std::result::Result<tonic::Response<stok::AccId>, tonic::Status> {
-        let acc = self.market.create_account();
+        let request = request.into_inner();
+        let margin = request.margin_ratio.map(|initial| account::Margin {
+            initial,
+            maintenance: request.maintenance_margin.unwrap_or(initial / 2.0),
+        });
+        let currency = parse_currency(&request.currency, "currency")?.unwrap_or_default();
+        let acc = self
+            .market
+            .create_account(margin, request.fee_tier as usize, currency)?;
 
         return Ok(Response::new(stok::AccId {
             id: Some(stok::Uuid {
@@ -88,10 +614,79 @@ impl market_server::Market for MyGreeter {
         request: tonic::Request<CreateSecReq>,
     ) -> std::result::Result<tonic::Response<stok::CreateSecResponse>, tonic::Status> {
         let request = request.into_inner();
-        let (founding_shares, founding_price) = (request.founding_shares, request.founding_price);
+        let founding_shares = parse_quantity(request.founding_shares, "founding shares")?;
+        let self_trade_prevention = request.self_trade_prevention();
+        let founding_price = parse_money(request.founding_price, "founding price")?;
+        let defaults = SecurityConfig::default();
+        let config = SecurityConfig {
+            currency: parse_currency(&request.currency, "currency")?.unwrap_or_default(),
+            tick_size: match request.tick_size {
+                Some(tick_size) => parse_money(Some(tick_size), "tick size")?,
+                None => defaults.tick_size,
+            },
+            lot_size: request
+                .lot_size
+                .map_or(defaults.lot_size, |lot| lot as usize),
+            breaker: BreakerConfig {
+                band: request.price_band.unwrap_or(defaults.breaker.band),
+                max_move: request.halt_move.unwrap_or(defaults.breaker.max_move),
+                window: request
+                    .halt_window_secs
+                    .map_or(defaults.breaker.window, Duration::from_secs),
+                cooloff: request
+                    .halt_cooloff_secs
+                    .map_or(defaults.breaker.cooloff, Duration::from_secs),
+            },
+            self_trade_prevention: match self_trade_prevention {
+                stok::SelfTradePrevention::CancelNewest => {
+                    market::SelfTradePrevention::CancelNewest
+                }
+                stok::SelfTradePrevention::CancelOldest => {
+                    market::SelfTradePrevention::CancelOldest
+                }
+                stok::SelfTradePrevention::CancelBoth => market::SelfTradePrevention::CancelBoth,
+                stok::SelfTradePrevention::Decrement => market::SelfTradePrevention::Decrement,
+            },
+            short_selling: match request.short_limit {
+                Some(short_limit) => Some(ShortSelling {
+                    max_value: parse_money(Some(short_limit), "short limit")?,
+                    borrow_rate: request.borrow_rate.unwrap_or(0.0),
+                }),
+                None => None,
+            },
+            fees: request
+                .fees
+                .into_iter()
+                .map(parse_fee_schedule)
+                .collect::<Result<_, _>>()?,
+            basket: if request.basket.is_empty() {
+                None
+            } else {
+                let mut components = Vec::with_capacity(request.basket.len());
+                for component in request.basket {
+                    let sec = SecId(parse_uuid(
+                        component.sec.and_then(|s| s.id),
+                        "basket component",
+                    )?);
+                    components.push((sec, component.quantity as usize));
+                }
+                Some(basket::Basket {
+                    components,
+                    unit_size: request.basket_unit as usize,
+                })
+            },
+            option: match request.option {
+                Some(option) => Some(parse_option(option)?),
+                None => None,
+            },
+            future: match request.future {
+                Some(future) => Some(parse_future(future)?),
+                None => None,
+            },
+        };
         let (sec, acc) = self
             .market
-            .create_security(founding_shares as usize, founding_price);
+            .create_security(founding_shares, founding_price, config)?;
 
         return Ok(Response::new(stok::CreateSecResponse {
             owner_acct: Some(stok::AccId {
@@ -126,34 +721,46 @@ impl market_server::Market for MyGreeter {
         } else {
             return Err(Status::data_loss("No security ID sent".to_string()));
         };
-        while let Some(_) = update_ping.next().await {
-            let value = self.market.current_value(SecId(sec));
-            let value = if let Ok(value) = value {
-                value
-            } else {
-                return Err(Status::failed_precondition(format!(
-                    "Security {} does not exist",
-                    sec
-                )));
-            };
+        let market = self.market.clone();
+        tokio::spawn(async move {
+            while update_ping.next().await.is_some() {
+                let value = market.current_value(SecId(sec)).and_then(|value| {
+                    Ok((
+                        value,
+                        market.is_halted(SecId(sec))?,
+                        market.is_delisted(SecId(sec))?,
+                        market.nav(SecId(sec))?,
+                    ))
+                });
+                let (value, halted, delisted, nav) = match value {
+                    Ok(value) => value,
+                    Err(e) => {
+                        let _ = tx.send(Err(e.into())).await;
+                        break;
+                    }
+                };
 
-            match tx
-                .send(Ok(SecValue {
-                    sec: Some(stok::SecId {
-                        id: Some(stok::Uuid {
-                            value: sec.to_string(),
+                match tx
+                    .send(Ok(SecValue {
+                        sec: Some(stok::SecId {
+                            id: Some(stok::Uuid {
+                                value: sec.to_string(),
+                            }),
                         }),
-                    }),
-                    value,
-                }))
-                .await
-            {
-                Ok(_) => {}
-                Err(_) => {
-                    break;
+                        value: Some(value.into()),
+                        halted,
+                        nav: nav.map(Into::into),
+                    }))
+                    .await
+                {
+                    Ok(_) if delisted => break,
+                    Ok(_) => {}
+                    Err(_) => {
+                        break;
+                    }
                 }
             }
-        }
+        });
 
         let output_stream = ReceiverStream::new(rx);
         Ok(Response::new(
@@ -180,7 +787,9 @@ impl market_server::Market for MyGreeter {
 
         let bid = self.market.get_lowest_bid_price(SecId(sec))?;
 
-        Ok(Response::new(LowestBid { price: bid }))
+        Ok(Response::new(LowestBid {
+            price: bid.map(Into::into),
+        }))
     }
     async fn get_highest_ask(
         &self,
@@ -201,7 +810,9 @@ impl market_server::Market for MyGreeter {
 
         let ask = self.market.get_highest_ask_price(SecId(sec))?;
 
-        Ok(Response::new(HighestAsk { price: ask }))
+        Ok(Response::new(HighestAsk {
+            price: ask.map(Into::into),
+        }))
     }
     async fn get_market_cap(
         &self,
@@ -222,79 +833,728 @@ impl market_server::Market for MyGreeter {
 
         let marketcap = self.market.market_cap(SecId(sec))?;
 
-        Ok(Response::new(MarketCap { marketcap }))
+        Ok(Response::new(MarketCap {
+            marketcap: Some(marketcap.into()),
+        }))
     }
     async fn place_ask(
         &self,
         request: tonic::Request<stok::Ask>,
     ) -> Result<tonic::Response<AskPlaced>, tonic::Status> {
         let req = request.into_inner();
+        let time_in_force = parse_time_in_force(req.time_in_force(), req.expires_at)?;
+        let price = parse_money(req.price, "price")?;
 
-        let sec = if let Some(id) = req.sec.map(|s| s.id).flatten() {
-            if let Ok(id) = Uuid::parse_str(&id.value) {
-                id
-            } else {
-                return Err(Status::data_loss(format!(
-                    "Invalid security ID sent: {}",
-                    id.value
-                )));
-            }
-        } else {
-            return Err(Status::data_loss("No security ID sent".to_string()));
-        };
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
 
-        let acc = if let Some(id) = req.acc.map(|s| s.id).flatten() {
-            if let Ok(id) = Uuid::parse_str(&id.value) {
-                id
-            } else {
-                return Err(Status::data_loss(format!(
-                    "Invalid account ID sent: {}",
-                    id.value
-                )));
-            }
-        } else {
-            return Err(Status::data_loss("No account ID sent".to_string()));
+        let display = match req.display_quantity {
+            0 => None,
+            display => Some(parse_quantity(display, "display quantity")?),
         };
+        // Clients from before lot sizes leave the quantity unset
+        let quantity = match req.quantity {
+            0 => self.market.lot_size(sec)?,
+            quantity => parse_quantity(quantity, "quantity")?,
+        };
+        self.limit_account(acc)?;
+        let order = self
+            .market
+            .place_ask(acc, sec, price, quantity, display, time_in_force)?;
 
-        self.market.place_ask(AccId(acc), SecId(sec), req.price)?;
-
-        Ok(Response::new(AskPlaced { price: req.price }))
+        Ok(Response::new(AskPlaced {
+            price: Some(price.into()),
+            quantity: quantity as u64,
+            order: Some(order.into()),
+        }))
     }
     async fn place_bid(
         &self,
         request: tonic::Request<stok::Bid>,
     ) -> Result<tonic::Response<BidPlaced>, tonic::Status> {
         let req = request.into_inner();
+        let time_in_force = parse_time_in_force(req.time_in_force(), req.expires_at)?;
+        let price = parse_money(req.price, "price")?;
 
-        let sec = if let Some(id) = req.sec.map(|s| s.id).flatten() {
-            if let Ok(id) = Uuid::parse_str(&id.value) {
-                id
-            } else {
-                return Err(Status::data_loss(format!(
-                    "Invalid security ID sent: {}",
-                    id.value
-                )));
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+
+        let display = match req.display_quantity {
+            0 => None,
+            display => Some(parse_quantity(display, "display quantity")?),
+        };
+        // Clients from before lot sizes leave the quantity unset
+        let quantity = match req.quantity {
+            0 => self.market.lot_size(sec)?,
+            quantity => parse_quantity(quantity, "quantity")?,
+        };
+        self.limit_account(acc)?;
+        let order = self
+            .market
+            .place_bid(acc, sec, price, quantity, display, time_in_force)?;
+
+        Ok(Response::new(BidPlaced {
+            price: Some(price.into()),
+            quantity: quantity as u64,
+            order: Some(order.into()),
+        }))
+    }
+
+    async fn replace_order(
+        &self,
+        request: tonic::Request<ReplaceOrderReq>,
+    ) -> Result<tonic::Response<OrderReplaced>, tonic::Status> {
+        let req = request.into_inner();
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+        self.limit_account(acc)?;
+        let order = OrderId(parse_uuid(req.order.and_then(|o| o.id), "order")?);
+        let price = parse_money(req.new_price, "price")?;
+        let quantity = parse_quantity(req.new_quantity, "quantity")?;
+
+        let kept_priority = self.market.replace_order(acc, order, price, quantity)?;
+
+        Ok(Response::new(OrderReplaced {
+            order: Some(order.into()),
+            price: Some(price.into()),
+            quantity: req.new_quantity,
+            kept_priority,
+        }))
+    }
+
+    async fn subscribe_executions(
+        &self,
+        request: tonic::Request<ExecutionsReq>,
+    ) -> Result<tonic::Response<Self::SubscribeExecutionsStream>, tonic::Status> {
+        let acc = AccId(parse_uuid(
+            request.into_inner().acc.and_then(|a| a.id),
+            "account",
+        )?);
+        if !self.market.has_account(acc) {
+            return Err(market::MarketError::AccDoesNotExist(acc).into());
+        }
+
+        let (tx, rx) = mpsc::channel(128);
+        let mut executions = self.market.subscribe_executions();
+        let mut statements = self.market.subscribe_statements();
+        tokio::spawn(async move {
+            loop {
+                // Cash posted to the account is reported along with its orders
+                let report: Result<ExecutionReport, u64> = tokio::select! {
+                    execution = executions.recv() => match execution {
+                        Ok(execution) if execution.account == acc => Ok(execution.into()),
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => Err(missed),
+                        Err(RecvError::Closed) => break,
+                    },
+                    entry = statements.recv() => match entry {
+                        Ok((entry_acc, entry)) if entry_acc == acc => Ok((acc, entry).into()),
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => Err(missed),
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+                let report = match report {
+                    Ok(report) => report,
+                    Err(missed) => {
+                        warn!(
+                            "Execution stream of account {} fell behind by {} reports",
+                            acc.0, missed
+                        );
+                        let _ = tx
+                            .send(Err(Status::data_loss(format!(
+                                "Missed {} execution reports",
+                                missed
+                            ))))
+                            .await;
+                        break;
+                    }
+                };
+                if tx.send(Ok(report)).await.is_err() {
+                    break;
+                }
             }
-        } else {
-            return Err(Status::data_loss("No security ID sent".to_string()));
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::SubscribeExecutionsStream
+        ))
+    }
+
+    async fn place_stop(
+        &self,
+        request: tonic::Request<Stop>,
+    ) -> Result<tonic::Response<StopPlaced>, tonic::Status> {
+        let req = request.into_inner();
+        let side = match req.side() {
+            stok::Side::Buy => bidask::Side::Buy,
+            stok::Side::Sell => bidask::Side::Sell,
+        };
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+        self.limit_account(acc)?;
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let trigger = parse_money(req.trigger_price, "trigger price")?;
+        let limit = match req.limit_price {
+            Some(limit) => Some(parse_money(Some(limit), "limit price")?),
+            None => None,
         };
 
-        let acc = if let Some(id) = req.acc.map(|s| s.id).flatten() {
-            if let Ok(id) = Uuid::parse_str(&id.value) {
-                id
-            } else {
-                return Err(Status::data_loss(format!(
-                    "Invalid account ID sent: {}",
-                    id.value
-                )));
+        let quantity = parse_quantity(req.quantity, "quantity")?;
+
+        let order = self
+            .market
+            .place_stop(acc, sec, side, trigger, limit, quantity)?;
+
+        Ok(Response::new(StopPlaced {
+            order: Some(order.into()),
+        }))
+    }
+
+    async fn cancel_stop(
+        &self,
+        request: tonic::Request<CancelStopReq>,
+    ) -> Result<tonic::Response<StopCanceled>, tonic::Status> {
+        let req = request.into_inner();
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+        self.limit_account(acc)?;
+        let order = OrderId(parse_uuid(req.order.and_then(|o| o.id), "order")?);
+
+        let quantity = self.market.cancel_stop(acc, order)?;
+
+        Ok(Response::new(StopCanceled {
+            order: Some(order.into()),
+            quantity: quantity as u64,
+        }))
+    }
+
+    async fn get_depth(
+        &self,
+        request: tonic::Request<DepthReq>,
+    ) -> Result<tonic::Response<Depth>, tonic::Status> {
+        let sec = SecId(parse_uuid(
+            request.into_inner().sec.and_then(|s| s.id),
+            "security",
+        )?);
+
+        let (bids, asks) = self.market.depth(sec)?;
+
+        Ok(Response::new(Depth {
+            bids: bids.into_iter().map(Into::into).collect(),
+            asks: asks.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_trades(
+        &self,
+        request: tonic::Request<TradesReq>,
+    ) -> Result<tonic::Response<TradeHistory>, tonic::Status> {
+        let req = request.into_inner();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let acc = match req.acc {
+            Some(acc) => Some(AccId(parse_uuid(acc.id, "account")?)),
+            None => None,
+        };
+        let limit = match req.limit {
+            0 => DEFAULT_TRADES,
+            limit => limit as usize,
+        };
+
+        let trades = self.market.trades(sec, acc, limit)?;
+
+        Ok(Response::new(TradeHistory {
+            trades: trades.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_candles(
+        &self,
+        request: tonic::Request<CandlesReq>,
+    ) -> Result<tonic::Response<Candles>, tonic::Status> {
+        let req = request.into_inner();
+        let interval = req.interval().into();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let from = parse_timestamp(req.from, "start time")?;
+        let to = parse_timestamp(req.to, "end time")?;
+
+        let candles = self.market.candles(sec, interval, from, to)?;
+
+        Ok(Response::new(Candles {
+            candles: candles
+                .into_iter()
+                .map(|candle| (sec, candle).into())
+                .collect(),
+        }))
+    }
+
+    async fn subscribe_candles(
+        &self,
+        request: tonic::Request<SubscribeCandlesReq>,
+    ) -> Result<tonic::Response<Self::SubscribeCandlesStream>, tonic::Status> {
+        let req = request.into_inner();
+        let interval: candles::CandleInterval = req.interval().into();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        if self.market.is_delisted(sec)? {
+            return Err(market::MarketError::Delisted(sec).into());
+        }
+
+        let (tx, rx) = mpsc::channel(128);
+        let mut candles = self.market.subscribe_candles();
+        let mut actions = self.market.subscribe_corporate_actions();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    candle = candles.recv() => match candle {
+                        Ok((candle_sec, candle))
+                            if candle_sec == sec && candle.interval == interval =>
+                        {
+                            if tx.send(Ok((sec, candle).into())).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(
+                                "Candle stream of security {} fell behind by {} updates",
+                                sec.0, missed
+                            );
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    action = actions.recv() => match action {
+                        Ok(action)
+                            if action.sec == sec
+                                && matches!(action.kind, ActionKind::Delisting { .. }) =>
+                        {
+                            debug!("Closing candle stream of delisted security {}", sec.0);
+                            break;
+                        }
+                        Err(RecvError::Closed) => break,
+                        _ => {}
+                    },
+                }
             }
-        } else {
-            return Err(Status::data_loss("No account ID sent".to_string()));
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::SubscribeCandlesStream
+        ))
+    }
+
+    async fn get_security_stats(
+        &self,
+        request: tonic::Request<SecurityStatsReq>,
+    ) -> Result<tonic::Response<SecurityStats>, tonic::Status> {
+        let sec = SecId(parse_uuid(
+            request.into_inner().sec.and_then(|s| s.id),
+            "security",
+        )?);
+
+        let stats = self.market.security_stats(sec)?;
+
+        Ok(Response::new(SecurityStats {
+            sec: Some(sec.into()),
+            session_end: Some(stats.ends.into()),
+            open: stats.open.map(Into::into),
+            high: stats.high.map(Into::into),
+            low: stats.low.map(Into::into),
+            last: Some(stats.last.into()),
+            previous_close: Some(stats.previous_close.into()),
+            volume: stats.volume as u64,
+            turnover: Some(stats.turnover.into()),
+            vwap: stats.vwap().map(Into::into),
+            trades: stats.trades as u64,
+            change_percent: stats.change_percent(),
+        }))
+    }
+
+    async fn get_shareholders(
+        &self,
+        request: tonic::Request<ShareholdersReq>,
+    ) -> Result<tonic::Response<Shareholders>, tonic::Status> {
+        let sec = SecId(parse_uuid(
+            request.into_inner().sec.and_then(|s| s.id),
+            "security",
+        )?);
+
+        let (outstanding, holders) = self.market.shareholders(sec)?;
+
+        Ok(Response::new(Shareholders {
+            shares_outstanding: outstanding as u64,
+            holders: holders
+                .into_iter()
+                .map(|(acc, shares)| Shareholder {
+                    acc: Some(acc.into()),
+                    shares,
+                    percent: if outstanding > 0 {
+                        shares as f64 / outstanding as f64 * 100.0
+                    } else {
+                        0.0
+                    },
+                })
+                .collect(),
+        }))
+    }
+
+    async fn list_indices(
+        &self,
+        _request: tonic::Request<ListIndicesReq>,
+    ) -> Result<tonic::Response<IndexList>, tonic::Status> {
+        Ok(Response::new(IndexList {
+            names: self.market.list_indices(),
+        }))
+    }
+
+    async fn get_index(
+        &self,
+        request: tonic::Request<IndexReq>,
+    ) -> Result<tonic::Response<IndexValue>, tonic::Status> {
+        let index = self.market.index(&request.into_inner().name)?;
+
+        Ok(Response::new(index.into()))
+    }
+
+    async fn subscribe_index(
+        &self,
+        request: tonic::Request<IndexReq>,
+    ) -> Result<tonic::Response<Self::SubscribeIndexStream>, tonic::Status> {
+        let name = request.into_inner().name;
+        // Subscribe before reading the current value so no change is missed
+        let mut values = self.market.subscribe_indices();
+        let current = self.market.index(&name)?;
+
+        let (tx, rx) = mpsc::channel(128);
+        let market = self.market.clone();
+        tokio::spawn(async move {
+            if tx.send(Ok(current.into())).await.is_err() {
+                return;
+            }
+            loop {
+                match values.recv().await {
+                    Ok((index_name, _)) if index_name == name => {
+                        // Membership may have changed along with the value
+                        let index = match market.index(&name) {
+                            Ok(index) => index,
+                            Err(e) => {
+                                let _ = tx.send(Err(e.into())).await;
+                                break;
+                            }
+                        };
+                        if tx.send(Ok(index.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Index stream of {} fell behind by {} updates", name, missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::SubscribeIndexStream
+        ))
+    }
+
+    async fn split_security(
+        &self,
+        request: tonic::Request<SplitReq>,
+    ) -> Result<tonic::Response<stok::CorporateAction>, tonic::Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+
+        let action = self.market.split(sec, req.to as usize, req.from as usize)?;
+
+        Ok(Response::new(action.into()))
+    }
+
+    async fn subscribe_corporate_actions(
+        &self,
+        request: tonic::Request<CorporateActionsReq>,
+    ) -> Result<tonic::Response<Self::SubscribeCorporateActionsStream>, tonic::Status> {
+        let sec = match request.into_inner().sec {
+            Some(sec) => Some(SecId(parse_uuid(sec.id, "security")?)),
+            None => None,
+        };
+
+        let (tx, rx) = mpsc::channel(128);
+        let mut actions = self.market.subscribe_corporate_actions();
+        tokio::spawn(async move {
+            loop {
+                match actions.recv().await {
+                    Ok(action) if sec.is_none_or(|sec| sec == action.sec) => {
+                        if tx.send(Ok(action.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Corporate action stream fell behind by {} actions", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::SubscribeCorporateActionsStream
+        ))
+    }
+
+    async fn declare_dividend(
+        &self,
+        request: tonic::Request<DividendReq>,
+    ) -> Result<tonic::Response<stok::CorporateAction>, tonic::Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let per_share = parse_money(req.amount_per_share, "amount per share")?;
+        let record = parse_timestamp(req.record_time, "record time")?
+            .ok_or_else(|| Status::data_loss("No record time sent".to_string()))?;
+        let pay = parse_timestamp(req.pay_time, "pay time")?
+            .ok_or_else(|| Status::data_loss("No pay time sent".to_string()))?;
+
+        let action = self.market.declare_dividend(sec, per_share, record, pay)?;
+
+        Ok(Response::new(action.into()))
+    }
+
+    async fn issue_shares(
+        &self,
+        request: tonic::Request<IssueSharesReq>,
+    ) -> Result<tonic::Response<SharesChanged>, tonic::Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let price = match req.price {
+            Some(price) => Some(parse_money(Some(price), "price")?),
+            None => None,
         };
 
-        self.market.place_bid(AccId(acc), SecId(sec), req.price)?;
+        let quantity = parse_quantity(req.quantity, "quantity")?;
+
+        let (action, order) = self.market.issue_shares(sec, quantity, price)?;
+
+        Ok(Response::new(SharesChanged {
+            action: Some(action.into()),
+            order: order.map(Into::into),
+        }))
+    }
+
+    async fn buyback_shares(
+        &self,
+        request: tonic::Request<BuybackSharesReq>,
+    ) -> Result<tonic::Response<SharesChanged>, tonic::Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let price = match req.price {
+            Some(price) => Some(parse_money(Some(price), "price")?),
+            None => None,
+        };
+
+        let quantity = parse_quantity(req.quantity, "quantity")?;
+
+        let (action, order) = self.market.buyback_shares(sec, quantity, price)?;
+
+        Ok(Response::new(SharesChanged {
+            action: Some(action.into()),
+            order: order.map(Into::into),
+        }))
+    }
+
+    async fn delist_security(
+        &self,
+        request: tonic::Request<DelistReq>,
+    ) -> Result<tonic::Response<stok::CorporateAction>, tonic::Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+        let price = match req.final_price {
+            Some(price) => Some(parse_money(Some(price), "final price")?),
+            None => None,
+        };
+
+        let action = self.market.delist(sec, price)?;
+
+        Ok(Response::new(action.into()))
+    }
+
+    async fn create_basket_shares(
+        &self,
+        request: tonic::Request<BasketSharesReq>,
+    ) -> Result<tonic::Response<BasketShares>, tonic::Status> {
+        let req = request.into_inner();
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+        self.limit_account(acc)?;
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+
+        let quantity = parse_quantity(req.quantity, "quantity")?;
+        let shares = self.market.create_basket_shares(acc, sec, quantity)?;
+
+        Ok(Response::new(BasketShares { shares }))
+    }
+
+    async fn redeem_basket_shares(
+        &self,
+        request: tonic::Request<BasketSharesReq>,
+    ) -> Result<tonic::Response<BasketShares>, tonic::Status> {
+        let req = request.into_inner();
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+        self.limit_account(acc)?;
+        let sec = SecId(parse_uuid(req.sec.and_then(|s| s.id), "security")?);
+
+        let quantity = parse_quantity(req.quantity, "quantity")?;
+        let shares = self.market.redeem_basket_shares(acc, sec, quantity)?;
+
+        Ok(Response::new(BasketShares { shares }))
+    }
+
+    async fn get_statement(
+        &self,
+        request: tonic::Request<StatementReq>,
+    ) -> Result<tonic::Response<Statement>, tonic::Status> {
+        let acc = AccId(parse_uuid(
+            request.into_inner().acc.and_then(|a| a.id),
+            "account",
+        )?);
+
+        let entries = self.market.statement(acc)?;
+
+        Ok(Response::new(Statement {
+            entries: entries.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn deposit(
+        &self,
+        request: tonic::Request<DepositReq>,
+    ) -> Result<tonic::Response<Balance>, tonic::Status> {
+        let req = request.into_inner();
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+        self.limit_account(acc)?;
+        let amount = parse_money(req.amount, "amount")?;
+
+        let currency = parse_currency(&req.currency, "currency")?;
+
+        let (currency, cash) = self.market.deposit(acc, currency, amount)?;
+
+        Ok(Response::new(Balance {
+            cash: Some(cash.into()),
+            currency: currency.to_string(),
+        }))
+    }
+
+    async fn subscribe_margin_calls(
+        &self,
+        request: tonic::Request<MarginCallsReq>,
+    ) -> Result<tonic::Response<Self::SubscribeMarginCallsStream>, tonic::Status> {
+        let acc = AccId(parse_uuid(
+            request.into_inner().acc.and_then(|a| a.id),
+            "account",
+        )?);
+        if !self.market.has_account(acc) {
+            return Err(market::MarketError::AccDoesNotExist(acc).into());
+        }
+
+        let (tx, rx) = mpsc::channel(16);
+        let mut margin_calls = self.market.subscribe_margin_calls();
+        tokio::spawn(async move {
+            loop {
+                match margin_calls.recv().await {
+                    Ok(call) if call.account == acc => {
+                        if tx.send(Ok(call.into())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(
+                            "Margin call stream of account {} fell behind by {} calls",
+                            acc.0, missed
+                        );
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
 
-        Ok(Response::new(BidPlaced { price: req.price }))
+        let output_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::SubscribeMarginCallsStream
+        ))
+    }
+
+    async fn set_fx_rate(
+        &self,
+        request: tonic::Request<FxRateReq>,
+    ) -> Result<tonic::Response<stok::FxRates>, tonic::Status> {
+        self.check_admin(&request)?;
+        let req = request.into_inner();
+        let currency = parse_currency(&req.currency, "currency")?
+            .ok_or_else(|| Status::data_loss("No currency sent".to_string()))?;
+
+        let rates = self.market.set_fx_rate(currency, req.rate)?;
+
+        Ok(Response::new(rates.into()))
+    }
+
+    async fn get_fx_rates(
+        &self,
+        _request: tonic::Request<FxRatesReq>,
+    ) -> Result<tonic::Response<stok::FxRates>, tonic::Status> {
+        Ok(Response::new(self.market.rates().into()))
+    }
+
+    async fn convert_cash(
+        &self,
+        request: tonic::Request<ConvertCashReq>,
+    ) -> Result<tonic::Response<CashConverted>, tonic::Status> {
+        let req = request.into_inner();
+        let acc = AccId(parse_uuid(req.acc.and_then(|a| a.id), "account")?);
+        self.limit_account(acc)?;
+        let from = parse_currency(&req.from, "currency to convert from")?
+            .ok_or_else(|| Status::data_loss("No currency to convert from sent".to_string()))?;
+        let to = parse_currency(&req.to, "currency to convert to")?
+            .ok_or_else(|| Status::data_loss("No currency to convert to sent".to_string()))?;
+        let amount = parse_money(req.amount, "amount")?;
+
+        let (from_cash, to_cash) =
+            self.market
+                .convert_cash(acc, from.clone(), to.clone(), amount)?;
+
+        Ok(Response::new(CashConverted {
+            from: Some(Balance {
+                cash: Some(from_cash.into()),
+                currency: from.to_string(),
+            }),
+            to: Some(Balance {
+                cash: Some(to_cash.into()),
+                currency: to.to_string(),
+            }),
+        }))
+    }
+
+    async fn get_portfolio_value(
+        &self,
+        request: tonic::Request<PortfolioValueReq>,
+    ) -> Result<tonic::Response<PortfolioValue>, tonic::Status> {
+        let acc = AccId(parse_uuid(
+            request.into_inner().acc.and_then(|a| a.id),
+            "account",
+        )?);
+
+        let (currency, value) = self.market.portfolio_value(acc)?;
+
+        Ok(Response::new(PortfolioValue {
+            value: Some(value.into()),
+            currency: currency.to_string(),
+        }))
     }
 }
 
@@ -311,10 +1571,25 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Time of day, in UTC, at which day orders expire
+const SESSION_CLOSE: Duration = Duration::from_secs(21 * 60 * 60);
+
 async fn app() {
     let (tx, mut rx) = watch::channel(());
 
-    let mut market = crate::Market::new(rx);
+    let limits = risk::RiskLimits::from_env();
+    info!("Enforcing risk limits {:?}", limits);
+    let indices = IndexConfig::from_env();
+    info!(
+        "Calculating indices {:?}",
+        indices.iter().map(|index| &index.name).collect::<Vec<_>>()
+    );
+    let mut market = crate::Market::new(rx, SESSION_CLOSE, limits.checks(), indices);
+
+    info!(
+        "Exchange fees are credited to account {}",
+        market.fee_account().0
+    );
 
     let updater_market = market.clone();
     let update_task = tokio::spawn(async move {
@@ -324,17 +1599,65 @@ async fn app() {
             interval.tick().await;
             trace!("Market update tick");
             let market = updater_market.clone();
-            Market::run_market_loop(market);
+            // Positions are marked to market as soon as the prices they are valued at move
+            if Market::run_market_loop(market) {
+                let calls = updater_market.liquidate_undermargined();
+                if calls > 0 {
+                    debug!("Issued {} margin calls", calls);
+                }
+            }
             tx.send(());
         }
     });
 
+    let sweeper_market = market.clone();
+    let sweeper_task = tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+            let expired = sweeper_market.expire_orders();
+            if expired > 0 {
+                debug!("Expired {} orders", expired);
+            }
+            sweeper_market.settle_borrows();
+            let paid = sweeper_market.pay_dividends();
+            if paid > 0 {
+                info!("Paid {} dividends", paid);
+            }
+            let expired = sweeper_market.exercise_options();
+            if expired > 0 {
+                info!("Expired {} option series", expired);
+            }
+            let settled = sweeper_market.settle_futures();
+            if settled > 0 {
+                info!("Settled {} futures series", settled);
+                let calls = sweeper_market.liquidate_undermargined();
+                if calls > 0 {
+                    debug!("Issued {} margin calls", calls);
+                }
+            }
+        }
+    });
+
     let addr = "0.0.0.0:50051".parse().unwrap();
-    let greeter = MyGreeter { market };
+    let admin_token = env::var("STOK_ADMIN_TOKEN").ok();
+    if admin_token.is_none() {
+        info!("STOK_ADMIN_TOKEN is unset; admin operations are disabled");
+    }
+    let rate_limit = ratelimit::RateLimitConfig::from_env();
+    info!("Rate limiting requests to {:?}", rate_limit);
+    let rate_limit = ratelimit::RateLimitLayer::new(rate_limit);
+    let greeter = MyGreeter {
+        market,
+        admin_token,
+        rate_limit: rate_limit.clone(),
+    };
 
     let server = Server::builder()
+        .layer(rate_limit)
         .add_service(crate::market_server::MarketServer::new(greeter))
         .serve(addr);
 
-    join!(update_task, server);
+    let _ = join!(update_task, sweeper_task, server);
 }
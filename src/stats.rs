@@ -0,0 +1,144 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::money::Money;
+
+/// Trading in a security over the current session, updated with each trade
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    /// When the session closes and the next one starts
+    pub ends: SystemTime,
+    pub open: Option<Money>,
+    pub high: Option<Money>,
+    pub low: Option<Money>,
+    pub last: Money,
+    /// Last price of the previous session, or the listing price in the first
+    pub previous_close: Money,
+    /// Shares traded
+    pub volume: usize,
+    /// Value of the shares traded
+    pub turnover: Money,
+    pub trades: usize,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new(Money::ZERO, UNIX_EPOCH)
+    }
+}
+
+impl SessionStats {
+    pub fn new(reference: Money, ends: SystemTime) -> Self {
+        Self {
+            ends,
+            open: None,
+            high: None,
+            low: None,
+            last: reference,
+            previous_close: reference,
+            volume: 0,
+            turnover: Money::ZERO,
+            trades: 0,
+        }
+    }
+
+    /// Starts a new session ending at `next_close` once the current one has closed
+    pub fn roll_if_due(&mut self, now: SystemTime, next_close: SystemTime) {
+        if now >= self.ends {
+            *self = Self::new(self.last, next_close);
+        }
+    }
+
+    pub fn record(&mut self, price: Money, quantity: usize) {
+        self.open.get_or_insert(price);
+        self.high = Some(self.high.map_or(price, |high| high.max(price)));
+        self.low = Some(self.low.map_or(price, |low| low.min(price)));
+        self.last = price;
+        self.volume = self.volume.saturating_add(quantity);
+        self.turnover = i64::try_from(quantity)
+            .ok()
+            .and_then(|quantity| price.checked_mul(quantity))
+            .and_then(|value| self.turnover.checked_add(value))
+            .unwrap_or(Money::MAX);
+        self.trades += 1;
+    }
+
+    /// Restates the session as if every `from` shares had been `to` shares all along
+    pub fn split(&mut self, to: usize, from: usize) {
+        let factor = from as f64 / to as f64;
+        self.open = self.open.map(|open| open.scale_by(factor));
+        self.high = self.high.map(|high| high.scale_by(factor));
+        self.low = self.low.map(|low| low.scale_by(factor));
+        self.last = self.last.scale_by(factor);
+        self.previous_close = self.previous_close.scale_by(factor);
+        self.volume = self.volume * to / from;
+    }
+
+    /// Volume weighted average price, rounded down to a minor unit
+    pub fn vwap(&self) -> Option<Money> {
+        (self.volume > 0).then(|| Money(self.turnover.minor_units() / self.volume as i64))
+    }
+
+    /// Change of the last price from the previous close, in percent
+    pub fn change_percent(&self) -> f64 {
+        if !self.previous_close.is_positive() {
+            return 0.0;
+        }
+        (self.last - self.previous_close).to_f64() / self.previous_close.to_f64() * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn record_tracks_the_session() {
+        let mut stats = SessionStats::new(Money(100_000), UNIX_EPOCH);
+        assert_eq!(stats.vwap(), None);
+        stats.record(Money(100_000), 1);
+        stats.record(Money(110_000), 2);
+        stats.record(Money(90_000), 1);
+        assert_eq!(stats.open, Some(Money(100_000)));
+        assert_eq!(stats.high, Some(Money(110_000)));
+        assert_eq!(stats.low, Some(Money(90_000)));
+        assert_eq!(stats.last, Money(90_000));
+        assert_eq!((stats.volume, stats.trades), (4, 3));
+        assert_eq!(stats.turnover, Money(410_000));
+        assert_eq!(stats.vwap(), Some(Money(102_500)));
+        assert_eq!(stats.change_percent(), -10.0);
+    }
+
+    #[test]
+    fn turnover_saturates() {
+        let mut stats = SessionStats::default();
+        stats.record(Money::MAX, 2);
+        assert_eq!(stats.turnover, Money::MAX);
+    }
+
+    #[test]
+    fn roll_starts_from_the_last_price() {
+        let close = UNIX_EPOCH + Duration::from_secs(60);
+        let mut stats = SessionStats::new(Money(100_000), close);
+        stats.record(Money(120_000), 5);
+        stats.roll_if_due(close - Duration::from_secs(1), close);
+        assert_eq!(stats.volume, 5);
+
+        let next = close + Duration::from_secs(60);
+        stats.roll_if_due(close, next);
+        assert_eq!(stats.ends, next);
+        assert_eq!(stats.previous_close, Money(120_000));
+        assert_eq!((stats.open, stats.volume), (None, 0));
+    }
+
+    #[test]
+    fn split_restates_prices_and_volume() {
+        let mut stats = SessionStats::new(Money(100_000), UNIX_EPOCH);
+        stats.record(Money(120_000), 5);
+        stats.split(2, 1);
+        assert_eq!(stats.open, Some(Money(60_000)));
+        assert_eq!(stats.previous_close, Money(50_000));
+        assert_eq!(stats.volume, 10);
+    }
+}